@@ -12,5 +12,6 @@ echo "Unzipping ..."
 7z x -y ${TMP_FILE}
 rm -f ${TMP_FILE}
 rm -rf ./$MUSIC_DIR/update
+mv -f ./$MUSIC_DIR/DOCUMENTS/Songlengths.md5 ./Songlengths.md5
 rm -rf ./$MUSIC_DIR/DOCUMENTS
 find ./$MUSIC_DIR ! -name '*.sid' -type f -exec rm -f {} +
@@ -0,0 +1,79 @@
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, time::Duration};
+
+/// Part of a subtune played over and over, from `start` (A) up to `end` (B).
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct LoopRegion {
+    pub start: Duration,
+    pub end: Duration,
+}
+
+impl LoopRegion {
+    /// `None` unless B comes after A.
+    pub fn new(start: Duration, end: Duration) -> Option<Self> {
+        Some(Self { start, end }).filter(Self::is_valid)
+    }
+
+    /// Whether B comes after A. The fields are public and loaded from
+    /// `loops.json`, so regions built without [`LoopRegion::new`] are
+    /// checked before use.
+    pub fn is_valid(&self) -> bool {
+        self.end > self.start
+    }
+}
+
+/// Saved loop regions by HVSC MD5 and song (`loops.json`).
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LoopStore {
+    pub tunes: HashMap<String, HashMap<u16, LoopRegion>>,
+}
+
+impl LoopStore {
+    pub fn get(&self, md5: &str, song: u16) -> Option<LoopRegion> {
+        self.tunes.get(md5)?.get(&song).copied()
+    }
+
+    /// Remembers `region` for `song`, or forgets the song's loop on `None`.
+    pub fn set(&mut self, md5: &str, song: u16, region: Option<LoopRegion>) {
+        match region {
+            Some(region) => {
+                self.tunes
+                    .entry(md5.to_string())
+                    .or_default()
+                    .insert(song, region);
+            }
+            None => {
+                if let Some(songs) = self.tunes.get_mut(md5) {
+                    songs.remove(&song);
+                    if songs.is_empty() {
+                        self.tunes.remove(md5);
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl LoopStore {
+    /// Drops regions that don't end after they start, so an edited file
+    /// can't make the player loop backwards.
+    pub fn load() -> Self {
+        let mut store: Self = crate::settings::load_json("loops.json");
+        for (md5, songs) in &mut store.tunes {
+            songs.retain(|song, region| {
+                let valid = region.is_valid();
+                if !valid {
+                    log::warn!("Ignoring loop of {md5} #{song} ending before it starts");
+                }
+                valid
+            });
+        }
+        store.tunes.retain(|_, songs| !songs.is_empty());
+        store
+    }
+
+    pub fn save(&self) -> anyhow::Result<()> {
+        crate::settings::save_json("loops.json", self)
+    }
+}
@@ -1,21 +1,94 @@
-use crate::{files, output::Output, sid_player::SidPlayer};
+use crate::files;
 use anyhow::Result;
+use cpal::BufferSize;
 use eframe::{
-    egui::{self, mutex::Mutex, CollapsingHeader, Context, ScrollArea, Ui},
+    egui::{self, CollapsingHeader, Context, ScrollArea, Ui},
     Frame,
 };
-use rb::{SpscRb, RB};
 use serde_json::Value;
 use sid_file::SidFile;
-use std::sync::Arc;
+use std::{
+    path::Path,
+    rc::Rc,
+    sync::mpsc::{self, Receiver},
+    time::Duration,
+};
+use vicious::{
+    ab_loop::{LoopRegion, LoopStore},
+    favorites::{Favorite, Favorites, Mark},
+    history::{History, Stats},
+    hvsc::{self, SidModel},
+    loop_detection::{self, DetectedLength, LengthCache},
+    loudness::{self, LoudnessCache, Normalization, TuneLoudness},
+    output::{self, DeviceOutput, OutputEvent, OutputSelection},
+    player_thread::{Command, PlayerThread},
+    playlist::{self, Entry, Playlist, Repeat},
+    playlist_file::{self, Locations},
+    radio::{self, Radio},
+    settings::Settings,
+    sid_player::SidPlayer,
+    silence::{SilenceAction, SilenceDetection},
+    sink::{Pipeline, SinkConfig},
+    snapshot::Snapshot,
+    songlength::{self, SongLengths},
+    transition::{Transition, TransitionMode},
+    volume::Volume,
+};
 
 pub struct App {
     sid_file: Option<SidFile>,
-    pub sid_player: Arc<Mutex<SidPlayer>>,
+    player: PlayerThread,
     status: String,
     json: Value,
-    _buffer: SpscRb<i16>,
-    _output: Output,
+    settings: Settings,
+    show_settings: bool,
+    show_diagnostics: bool,
+    show_stats: bool,
+    devices: Vec<String>,
+    default_device: String,
+    device: Option<DeviceOutput>,
+    /// HVSC MD5 of the loaded tune.
+    md5: Option<String>,
+    /// Full file MD5 of the loaded tune, which newer song-length databases
+    /// use instead.
+    full_md5: String,
+    loudness: LoudnessCache,
+    /// Song and mode the normalization gain was last sent for.
+    normalized: Option<(u16, Normalization)>,
+    scan: Option<Receiver<(String, TuneLoudness)>>,
+    song_lengths: SongLengths,
+    /// Lengths found by loop detection, for tunes `song_lengths` lacks.
+    detected_lengths: LengthCache,
+    length_scan: Option<Receiver<(String, Vec<Option<DetectedLength>>)>>,
+    /// Position the seek slider is being dragged to.
+    seek_position: Option<f64>,
+    /// Snapshot on its way back from the player, to be saved.
+    pending_snapshot: Option<Receiver<Box<Snapshot>>>,
+    /// Tempo in percent, not persisted.
+    speed: u32,
+    loops: LoopStore,
+    /// A point marked for a loop that has no B yet.
+    loop_start: Option<Duration>,
+    playlist: Playlist,
+    /// M3U, PLS or JSON file to import the playlist from or export it to.
+    playlist_file: String,
+    /// Collection path of the loaded tune.
+    path: Option<String>,
+    /// Picks what plays next while on. Away on a background thread while
+    /// picking.
+    radio: Option<Radio>,
+    /// The radio with its pick and whether to transition to it.
+    radio_pick: Option<Receiver<(Radio, Option<Entry>, bool)>>,
+    favorites: Favorites,
+    /// `favorites.starred()`, rebuilt when a mark changes.
+    starred: Rc<[Favorite]>,
+    history: History,
+    /// Views of the history, rebuilt when a play starts or ends rather than
+    /// every frame.
+    recent: Rc<[(Entry, String)]>,
+    stats: Stats,
+    /// Where the history is exported to for ListenBrainz.
+    listens_file: String,
 }
 
 impl Default for App {
@@ -25,43 +98,1116 @@ impl Default for App {
 }
 
 impl App {
+    /// Span of the position bar for songs of unknown length.
+    const DEFAULT_SEEK_RANGE: Duration = Duration::from_secs(180);
+    /// Subtunes listed under "Recently played".
+    const RECENT_COUNT: usize = 50;
+    /// Latencies offered in the settings. Changing it rebuilds the pipeline,
+    /// so it's a choice of presets rather than a slider.
+    const LATENCIES_MS: [u32; 6] = [20, 50, 100, 200, 500, 1000];
+
     pub fn new() -> Result<Self> {
         let sid_file = None;
-        let buffer: SpscRb<i16> = SpscRb::new(44100 * 2);
-        let output = Output::new(buffer.consumer())?;
-        let sid_player = SidPlayer::new(buffer.producer(), output.sample_rate());
-        let status = "Started...".to_owned();
+        let settings = Settings::load();
+        let pipeline = Pipeline::build(&settings.sink, &settings.output)?;
+        let status = format!(
+            "Started on {}",
+            Self::describe_output(pipeline.device.as_ref())
+        );
+        let mut sid_player = SidPlayer::new(pipeline.sink);
+        sid_player.set_volume(settings.volume);
+        sid_player.set_transition(settings.transition);
+        sid_player.set_silence_detection(settings.silence);
+        let player = PlayerThread::spawn(sid_player, None);
         let json = files::files();
-
-        let sid_player = Arc::new(Mutex::new(sid_player));
+        let favorites = Favorites::load();
+        let history = History::load();
 
         Ok(Self {
             sid_file,
-            sid_player,
+            player,
             status,
             json,
-            _buffer: buffer,
-            _output: output,
+            settings,
+            show_settings: false,
+            show_diagnostics: false,
+            show_stats: false,
+            devices: Vec::new(),
+            default_device: String::new(),
+            device: pipeline.device,
+            md5: None,
+            full_md5: String::new(),
+            loudness: LoudnessCache::load(),
+            normalized: None,
+            scan: None,
+            song_lengths: files::song_lengths(),
+            detected_lengths: LengthCache::load(),
+            length_scan: None,
+            seek_position: None,
+            pending_snapshot: None,
+            speed: SidPlayer::NORMAL_SPEED,
+            loops: LoopStore::load(),
+            loop_start: None,
+            playlist: Playlist::load(&Playlist::default_path()).unwrap_or_default(),
+            playlist_file: String::new(),
+            path: None,
+            radio: None,
+            radio_pick: None,
+            starred: favorites.starred().into(),
+            favorites,
+            recent: history.recent(Self::RECENT_COUNT).into(),
+            stats: history.stats(),
+            history,
+            listens_file: "listens.json".to_owned(),
         })
     }
 
-    pub fn load(&mut self, filename: &str) -> Result<()> {
+    fn describe_output(device: Option<&DeviceOutput>) -> String {
+        let Some(device) = device else {
+            return "null output".to_owned();
+        };
+        let Some(output) = device.output() else {
+            return "no output device".to_owned();
+        };
+        format!(
+            "{} ({} Hz, {} ch, {})",
+            output.device_name(),
+            output.sample_rate(),
+            output.stream_config().channels,
+            output.sample_format()
+        )
+    }
+
+    /// Rebuilds the pipeline for another sink, handing the new sink to the
+    /// player without stopping the tune.
+    fn switch_sink(&mut self, config: SinkConfig) {
+        match Pipeline::build(&config, &self.settings.output) {
+            Ok(pipeline) => {
+                self.player.send(Command::SetSink(pipeline.sink));
+                self.device = pipeline.device;
+                self.status = format!(
+                    "[OK] Switched to {}",
+                    Self::describe_output(self.device.as_ref())
+                );
+                self.settings.sink = config;
+                self.save_settings();
+            }
+            Err(err) => self.status = format!("[ERROR] Can't open output: {err}"),
+        }
+    }
+
+    /// Moves the device output to another host or device while the tune
+    /// keeps playing. The player re-tunes the SID to the new sample rate.
+    pub fn switch_output(&mut self, selection: OutputSelection) {
+        let Some(device) = &mut self.device else {
+            self.settings.output = selection;
+            self.save_settings();
+            return;
+        };
+        if let Err(err) = device.switch(&selection) {
+            self.status = format!("[ERROR] Can't open output: {err}");
+            return;
+        }
+        self.status = format!(
+            "[OK] Switched to {}",
+            Self::describe_output(self.device.as_ref())
+        );
+        self.settings.output = selection;
+        self.save_settings();
+    }
+
+    /// Hands the volume to the player, saving it only when `persist` is set
+    /// so dragging the slider doesn't write the settings every frame.
+    fn set_volume(&mut self, volume: Volume, persist: bool) {
+        if volume != self.settings.volume {
+            self.player.send(Command::SetVolume(volume));
+            self.settings.volume = volume;
+        }
+        if persist {
+            self.save_settings();
+        }
+    }
+
+    fn save_settings(&mut self) {
+        if let Err(err) = self.settings.save() {
+            self.status = format!("[ERROR] Can't save settings: {err}");
+        }
+    }
+
+    /// Surfaces stream errors in the status bar while the device output
+    /// reconnects. The player keeps its position meanwhile.
+    fn check_output(&mut self) {
+        let Some(device) = &mut self.device else {
+            return;
+        };
+        while let Some(event) = device.poll() {
+            self.status = match event {
+                OutputEvent::Error(err) => format!("[ERROR] Audio output: {err}"),
+                OutputEvent::Reconnected => {
+                    format!(
+                        "[OK] Reconnected to {}",
+                        Self::describe_output(Some(device))
+                    )
+                }
+                OutputEvent::ReconnectFailed(err, delay) => {
+                    format!("[ERROR] Can't reopen output: {err}, retrying in {delay:?}")
+                }
+            };
+        }
+    }
+
+    fn refresh_devices(&mut self) {
+        let host = self.settings.output.host.as_deref();
+        self.devices = output::devices(host).unwrap_or_default();
+        self.default_device = match output::default_device(host) {
+            Some(name) => format!("Default ({name})"),
+            None => "Default".to_owned(),
+        };
+    }
+
+    fn settings_window(&mut self, ctx: &Context) {
+        let mut open = self.show_settings;
+        let mut selection = self.settings.output.clone();
+        let mut sink = self.settings.sink.clone();
+        let mut volume = self.settings.volume;
+        let mut normalization = self.settings.normalization;
+        let mut transition = self.settings.transition;
+        let mut silence = self.settings.silence;
+        egui::Window::new("Settings")
+            .open(&mut open)
+            .resizable(false)
+            .show(ctx, |ui| {
+                egui::Grid::new("output_settings")
+                    .num_columns(2)
+                    .show(ui, |ui| {
+                        ui.label("Output:");
+                        ui.horizontal(|ui| {
+                            ui.selectable_value(&mut sink, SinkConfig::Device, "Sound device");
+                            ui.selectable_value(
+                                &mut sink,
+                                SinkConfig::Null { realtime: true },
+                                "Null",
+                            );
+                        });
+                        ui.end_row();
+                        ui.label("Host:");
+                        egui::ComboBox::from_id_salt("host")
+                            .selected_text(selection.host.as_deref().unwrap_or("Default"))
+                            .show_ui(ui, |ui| {
+                                ui.selectable_value(&mut selection.host, None, "Default");
+                                for host in output::hosts() {
+                                    ui.selectable_value(
+                                        &mut selection.host,
+                                        Some(host.to_owned()),
+                                        host,
+                                    );
+                                }
+                            });
+                        ui.end_row();
+                        ui.label("Device:");
+                        egui::ComboBox::from_id_salt("device")
+                            .selected_text(
+                                selection.device.as_deref().unwrap_or(&self.default_device),
+                            )
+                            .show_ui(ui, |ui| {
+                                ui.selectable_value(
+                                    &mut selection.device,
+                                    None,
+                                    &self.default_device,
+                                );
+                                for device in &self.devices {
+                                    ui.selectable_value(
+                                        &mut selection.device,
+                                        Some(device.clone()),
+                                        device,
+                                    );
+                                }
+                            });
+                        ui.end_row();
+                        ui.label("Latency:");
+                        egui::ComboBox::from_id_salt("latency")
+                            .selected_text(format!("{} ms", selection.latency_ms))
+                            .show_ui(ui, |ui| {
+                                for latency in Self::LATENCIES_MS {
+                                    ui.selectable_value(
+                                        &mut selection.latency_ms,
+                                        latency,
+                                        format!("{latency} ms"),
+                                    );
+                                }
+                            });
+                        ui.end_row();
+                        ui.label("Pause:");
+                        ui.checkbox(&mut selection.stop_when_paused, "Stop the stream");
+                        ui.end_row();
+                        ui.label("Normalize:");
+                        ui.horizontal(|ui| {
+                            ui.selectable_value(&mut normalization, Normalization::Off, "Off");
+                            ui.selectable_value(&mut normalization, Normalization::Track, "Track");
+                            ui.selectable_value(&mut normalization, Normalization::Album, "Album");
+                        });
+                        ui.end_row();
+                        ui.label("Limiter:");
+                        ui.checkbox(&mut volume.limiter, "Soft-clip loud peaks");
+                        ui.end_row();
+                        ui.label("Transition:");
+                        ui.horizontal(|ui| {
+                            use TransitionMode::*;
+                            for (mode, name) in [
+                                (Cut, "Cut"),
+                                (Gapless, "Gapless"),
+                                (Fade, "Fade"),
+                                (Crossfade, "Crossfade"),
+                            ] {
+                                ui.selectable_value(&mut transition.mode, mode, name);
+                            }
+                        });
+                        ui.end_row();
+                        ui.label("Fade length:");
+                        ui.add_enabled(
+                            matches!(
+                                transition.mode,
+                                TransitionMode::Fade | TransitionMode::Crossfade
+                            ),
+                            egui::Slider::new(
+                                &mut transition.duration_ms,
+                                Transition::MIN_DURATION_MS..=Transition::MAX_DURATION_MS,
+                            )
+                            .logarithmic(true)
+                            .suffix(" ms"),
+                        );
+                        ui.end_row();
+                        ui.label("Silence:");
+                        ui.horizontal(|ui| {
+                            ui.checkbox(&mut silence.enabled, "Skip after");
+                            ui.add_enabled(
+                                silence.enabled,
+                                egui::DragValue::new(&mut silence.duration_ms)
+                                    .range(
+                                        SilenceDetection::MIN_DURATION_MS
+                                            ..=SilenceDetection::MAX_DURATION_MS,
+                                    )
+                                    .speed(100)
+                                    .suffix(" ms"),
+                            );
+                            ui.label("below");
+                            ui.add_enabled(
+                                silence.enabled,
+                                egui::DragValue::new(&mut silence.threshold_db)
+                                    .range(
+                                        SilenceDetection::MIN_THRESHOLD_DB
+                                            ..=SilenceDetection::MAX_THRESHOLD_DB,
+                                    )
+                                    .suffix(" dB"),
+                            );
+                        });
+                        ui.end_row();
+                        ui.label("");
+                        ui.add_enabled_ui(silence.enabled, |ui| {
+                            ui.horizontal(|ui| {
+                                ui.selectable_value(
+                                    &mut silence.action,
+                                    SilenceAction::NextSong,
+                                    "Next subtune",
+                                );
+                                ui.selectable_value(
+                                    &mut silence.action,
+                                    SilenceAction::NextTune,
+                                    "Next tune",
+                                );
+                                ui.checkbox(&mut silence.ignore_dc, "Ignore DC offset")
+                                    .on_hover_text("Level around the constant 6581 offset");
+                            });
+                        });
+                        ui.end_row();
+                    });
+                if ui.button("Refresh devices").clicked() {
+                    self.refresh_devices();
+                }
+            });
+        self.show_settings = open;
+
+        if volume != self.settings.volume {
+            self.set_volume(volume, true);
+        }
+        if normalization != self.settings.normalization {
+            self.settings.normalization = normalization;
+            self.save_settings();
+        }
+        if transition != self.settings.transition {
+            self.player.send(Command::SetTransition(transition));
+            self.settings.transition = transition;
+            self.save_settings();
+        }
+        if silence != self.settings.silence {
+            self.player.send(Command::SetSilenceDetection(silence));
+            self.settings.silence = silence;
+            self.save_settings();
+        }
+        if sink != self.settings.sink {
+            self.switch_sink(sink);
+        }
+        if selection.latency_ms != self.settings.output.latency_ms {
+            // The ring buffer is sized on open, so this needs a new pipeline.
+            self.settings.output.latency_ms = selection.latency_ms;
+            self.switch_sink(self.settings.sink.clone());
+        }
+        if selection.host != self.settings.output.host {
+            selection.device = None;
+        }
+        if selection != self.settings.output {
+            let host_changed = selection.host != self.settings.output.host;
+            self.switch_output(selection);
+            if host_changed {
+                self.refresh_devices();
+            }
+        }
+    }
+
+    /// Asks the player for a snapshot, saved by [`Self::check_snapshot`]
+    /// as the quick save of the tune.
+    fn save_snapshot(&mut self) {
+        let (sender, receiver) = mpsc::channel();
+        self.player.send(Command::Snapshot(sender));
+        self.pending_snapshot = Some(receiver);
+    }
+
+    fn check_snapshot(&mut self) {
+        let Some(Ok(snapshot)) = self.pending_snapshot.as_ref().map(Receiver::try_recv) else {
+            return;
+        };
+        self.pending_snapshot = None;
+        let Some(md5) = &self.md5 else {
+            return;
+        };
+        self.status = match snapshot.save(&Snapshot::quick_save_path(md5)) {
+            Ok(()) => format!(
+                "[OK] Saved song {} at {}",
+                snapshot.song(),
+                songlength::format_time(snapshot.position())
+            ),
+            Err(err) => format!("[ERROR] Can't save snapshot: {err}"),
+        };
+    }
+
+    fn load_snapshot(&mut self) {
+        let Some(md5) = &self.md5 else {
+            return;
+        };
+        self.status = match Snapshot::load(&Snapshot::quick_save_path(md5)) {
+            Ok(snapshot) => {
+                let status = format!(
+                    "[OK] Restored song {} at {}",
+                    snapshot.song(),
+                    songlength::format_time(snapshot.position())
+                );
+                self.player.send(Command::Restore(Box::new(snapshot)));
+                status
+            }
+            Err(err) => format!("[ERROR] Can't load snapshot: {err}"),
+        };
+    }
+
+    fn song_length(&self, song: u16) -> Option<Duration> {
+        let md5 = self.md5.as_ref()?;
+        self.song_lengths
+            .get_either(md5, &self.full_md5, song)
+            .or_else(|| self.detected_lengths.get(md5, song))
+    }
+
+    /// Song number, elapsed time and a slider for seeking. Without a known
+    /// length the slider spans [`Self::DEFAULT_SEEK_RANGE`], growing with
+    /// the position.
+    fn position_bar(&mut self, ui: &mut Ui) {
+        let status = self.player.status().clone();
+        if status.songs == 0 {
+            return;
+        }
+        ui.label(format!("{}/{}", status.song, status.songs));
+
+        let length = self.song_length(status.song);
+        let mut position = self.seek_position.unwrap_or(status.position.as_secs_f64());
+        let end = length.map_or(
+            Self::DEFAULT_SEEK_RANGE.as_secs_f64().max(position),
+            |length| length.as_secs_f64(),
+        );
+        let slider = ui.add(egui::Slider::new(&mut position, 0.0..=end).show_value(false));
+        if slider.dragged() {
+            self.seek_position = Some(position);
+        } else if slider.drag_stopped() || slider.changed() {
+            self.player
+                .send(Command::Seek(Duration::from_secs_f64(position)));
+            self.seek_position = None;
+        }
+
+        let elapsed = songlength::format_time(Duration::from_secs_f64(position));
+        match length {
+            Some(length) => ui.label(format!("{elapsed} / {}", songlength::format_time(length))),
+            None => ui.label(elapsed),
+        };
+    }
+
+    /// A and B buttons marking a loop at the current position, one to stop
+    /// looping and one to bring back the loop saved for the song.
+    fn loop_controls(&mut self, ui: &mut Ui) {
+        let status = self.player.status().clone();
+        let Some(md5) = self.md5.clone().filter(|_| status.songs > 0) else {
+            return;
+        };
+        if self.loop_start.is_some_and(|start| status.position < start) {
+            self.loop_start = None;
+        }
+
+        let marked = self.loop_start.is_some();
+        if ui
+            .selectable_label(marked, "A")
+            .on_hover_text("Start the loop here")
+            .clicked()
+        {
+            self.loop_start = Some(status.position);
+        }
+        let region = self
+            .loop_start
+            .and_then(|start| LoopRegion::new(start, status.position));
+        if ui
+            .add_enabled(region.is_some(), egui::Button::new("B"))
+            .on_hover_text("End the loop here")
+            .clicked()
+        {
+            self.loop_start = None;
+            if let Some(region) = region {
+                self.set_loop(&md5, status.song, region);
+            }
+        }
+
+        let saved = self.loops.get(&md5, status.song);
+        match status.ab_loop {
+            Some(region) => {
+                ui.label(format!(
+                    "🔁 {}–{}",
+                    songlength::format_time(region.start),
+                    songlength::format_time(region.end)
+                ));
+                if ui.button("✖").on_hover_text("Stop looping").clicked() {
+                    self.player.send(Command::SetLoop(None));
+                }
+            }
+            None => {
+                if ui
+                    .add_enabled(saved.is_some(), egui::Button::new("🔁"))
+                    .on_hover_text("Loop the saved region")
+                    .clicked()
+                {
+                    self.player.send(Command::SetLoop(saved));
+                }
+            }
+        }
+    }
+
+    /// Loops `region` and remembers it for the song.
+    fn set_loop(&mut self, md5: &str, song: u16, region: LoopRegion) {
+        self.player.send(Command::SetLoop(Some(region)));
+        self.loops.set(md5, song, Some(region));
+        if let Err(err) = self.loops.save() {
+            self.status = format!("[ERROR] Can't save loop: {err}");
+        }
+    }
+
+    /// Tempo slider, reset to normal speed by double clicking it.
+    fn speed_control(&mut self, ui: &mut Ui) {
+        let mut speed = self.speed;
+        let slider = ui
+            .add(
+                egui::Slider::new(&mut speed, SidPlayer::MIN_SPEED..=SidPlayer::MAX_SPEED)
+                    .logarithmic(true)
+                    .suffix("%"),
+            )
+            .on_hover_text("Tempo, double click for normal speed");
+        if slider.double_clicked() {
+            speed = SidPlayer::NORMAL_SPEED;
+        }
+        if speed != self.speed {
+            self.speed = speed;
+            self.player.send(Command::SetSpeed(speed));
+        }
+    }
+
+    /// Mute button and volume slider, which also follows the mouse wheel
+    /// while hovered.
+    fn volume_control(&mut self, ui: &mut Ui) {
+        let mut volume = self.settings.volume;
+        let icon = if volume.muted { "🔇" } else { "🔊" };
+        let mute = ui.button(icon);
+        if mute.clicked() {
+            volume.muted = !volume.muted;
+        }
+        let slider = ui.add(
+            egui::Slider::new(&mut volume.db, Volume::MIN_DB..=Volume::MAX_DB)
+                .suffix(" dB")
+                .step_by(0.5),
+        );
+        let scroll = ui.input(|input| input.raw_scroll_delta.y);
+        if (slider.hovered() || mute.hovered()) && scroll != 0.0 {
+            volume.adjust(scroll.signum() * Volume::STEP_DB);
+        }
+        if volume != self.settings.volume || slider.drag_stopped() {
+            self.set_volume(volume, !slider.dragged());
+        }
+    }
+
+    fn diagnostics_window(&mut self, ctx: &Context) {
+        let mut open = self.show_diagnostics;
+        egui::Window::new("Diagnostics")
+            .open(&mut open)
+            .resizable(false)
+            .show(ctx, |ui| {
+                let Some(device) = &self.device else {
+                    ui.label("No sound device in use.");
+                    return;
+                };
+                egui::Grid::new("diagnostics")
+                    .num_columns(2)
+                    .show(ui, |ui| {
+                        ui.label("Output:");
+                        ui.label(Self::describe_output(Some(device)));
+                        ui.end_row();
+                        if let Some(output) = device.output() {
+                            ui.label("Device buffer:");
+                            ui.label(match output.stream_config().buffer_size {
+                                BufferSize::Fixed(frames) => format!("{frames} frames"),
+                                BufferSize::Default => "default".to_owned(),
+                            });
+                            ui.end_row();
+                        }
+                        ui.label("Buffered:");
+                        ui.label(format!(
+                            "{} / {} ms",
+                            device.buffered().as_millis(),
+                            device.latency().as_millis()
+                        ));
+                        ui.end_row();
+                        ui.label("Underruns:");
+                        ui.label(device.stats().underruns().to_string());
+                        ui.end_row();
+                        ui.label("Overruns:");
+                        ui.label(device.stats().overruns().to_string());
+                        ui.end_row();
+                    });
+            });
+        self.show_diagnostics = open;
+    }
+
+    /// Loads `song` of a tune (its start song when `None`), with the
+    /// configured transition when the playlist moves on by itself.
+    pub fn load(&mut self, filename: &str, song: Option<u16>, transition: bool) -> Result<()> {
         let data = files::open(filename);
         let sid_file = SidFile::parse(&data)?;
 
-        self.sid_player.lock().load_data(
-            &sid_file.data,
-            sid_file.real_load_address,
-            sid_file.init_address,
-            sid_file.play_address,
-            sid_file.songs,
-            sid_file.start_song,
-        );
+        if transition {
+            self.player
+                .send(Command::Transition(Box::new(sid_file.clone()), song));
+        } else {
+            self.player.send(Command::Load(Box::new(sid_file.clone())));
+            if let Some(song) = song {
+                self.player.send(Command::SelectSong(song));
+            }
+        }
         self.sid_file = Some(sid_file);
-        self.sid_player.lock().play();
+        self.path = Some(filename.to_owned());
+        self.history.finish();
+        self.refresh_history();
+        self.md5 = hvsc::md5(&data).ok();
+        self.full_md5 = hvsc::full_md5(&data);
+        if self
+            .md5
+            .as_ref()
+            .is_some_and(|md5| self.favorites.relocate(md5, filename))
+        {
+            self.save_favorites();
+        }
+        self.normalized = None;
         Ok(())
     }
 
+    fn play_entry(&mut self, entry: &Entry, transition: bool) {
+        self.status = match self.load(&entry.path, entry.song, transition) {
+            Ok(()) => format!("[OK] {} loaded!", entry.title()),
+            Err(_) => format!("[ERROR] Can't load {}!", entry.title()),
+        };
+    }
+
+    /// Moves on to the next entry of the queue or playlist once the song
+    /// has played for its length.
+    fn follow_playlist(&mut self) {
+        if !self.player.status_is_current() {
+            return;
+        }
+        let status = self.player.status().clone();
+        let silence = self.settings.silence;
+        let silent = silence.triggered(&status);
+        if silent && silence.action == SilenceAction::NextSong && status.song < status.songs {
+            self.player.send(Command::SelectSong(status.song + 1));
+            return;
+        }
+        if !silent && !playlist::song_over(&status, self.song_length(status.song)) {
+            return;
+        }
+        if self.radio_on() {
+            self.tune_in(true);
+        } else if let Some(entry) = self.playlist.advance() {
+            self.play_entry(&entry, true);
+        }
+    }
+
+    fn radio_on(&self) -> bool {
+        self.radio.is_some() || self.radio_pick.is_some()
+    }
+
+    /// Has the radio pick what plays next on a background thread, as that
+    /// may read hundreds of files. Does nothing while it's still picking.
+    fn tune_in(&mut self, transition: bool) {
+        let Some(mut radio) = self.radio.take() else {
+            return;
+        };
+        let last_played = self.history.last_played();
+        let (sender, receiver) = mpsc::channel();
+        std::thread::spawn(move || {
+            let entry = radio.pick(|path| Some(files::open(path)), &last_played);
+            let _ = sender.send((radio, entry, transition));
+        });
+        self.radio_pick = Some(receiver);
+    }
+
+    /// Plays the radio's pick once it's there.
+    fn check_radio_pick(&mut self) {
+        let Some(Ok((mut radio, entry, transition))) =
+            self.radio_pick.as_ref().map(Receiver::try_recv)
+        else {
+            return;
+        };
+        self.radio_pick = None;
+        let Some(entry) = entry else {
+            self.status = "[ERROR] No tune matches the radio filter".to_owned();
+            return;
+        };
+        // The filter may have changed while it was picking.
+        if radio.filter() != &self.settings.radio {
+            radio.set_filter(self.settings.radio.clone());
+        }
+        self.radio = Some(radio);
+        self.play_entry(&entry, transition);
+    }
+
+    /// Radio switch and filter. Filter changes apply from the next pick.
+    fn radio_panel(&mut self, ui: &mut Ui) {
+        if cfg!(target_arch = "wasm32") {
+            // Picking reads tunes synchronously, which the web version can't.
+            ui.label("Not available in the browser.");
+            return;
+        }
+        let mut on = self.radio_on();
+        if ui.checkbox(&mut on, "Play random tunes").changed() {
+            self.radio =
+                on.then(|| Radio::new(radio::tree_paths(&self.json), self.settings.radio.clone()));
+            // A pick still on its way is dropped with its radio.
+            self.radio_pick = None;
+            self.tune_in(false);
+        }
+
+        let mut filter = self.settings.radio.clone();
+        egui::Grid::new("radio_filter")
+            .num_columns(2)
+            .show(ui, |ui| {
+                ui.label("Composer:");
+                ui.text_edit_singleline(&mut filter.composer);
+                ui.end_row();
+
+                ui.label("Years:");
+                ui.horizontal(|ui| {
+                    optional_value(ui, &mut filter.from_year, 1982);
+                    ui.label("to");
+                    optional_value(ui, &mut filter.to_year, 2025);
+                });
+                ui.end_row();
+
+                ui.label("SID model:");
+                let name = |model: Option<SidModel>| match model {
+                    None => "Any",
+                    Some(SidModel::Mos6581) => "6581",
+                    Some(SidModel::Mos8580) => "8580",
+                    Some(_) => "Both",
+                };
+                egui::ComboBox::from_id_salt("radio_model")
+                    .selected_text(name(filter.model))
+                    .show_ui(ui, |ui| {
+                        for model in [None, Some(SidModel::Mos6581), Some(SidModel::Mos8580)] {
+                            ui.selectable_value(&mut filter.model, model, name(model));
+                        }
+                    });
+                ui.end_row();
+
+                ui.label("Not played for:");
+                ui.horizontal(|ui| {
+                    optional_value(ui, &mut filter.not_played_days, 7);
+                    ui.label("days");
+                });
+                ui.end_row();
+            });
+
+        if filter != self.settings.radio {
+            if let Some(radio) = &mut self.radio {
+                radio.set_filter(filter.clone());
+            }
+            self.settings.radio = filter;
+            self.save_settings();
+        }
+    }
+
+    fn save_playlist(&mut self) {
+        if let Err(err) = self.playlist.save(&Playlist::default_path()) {
+            self.status = format!("[ERROR] Can't save playlist: {err}");
+        }
+    }
+
+    /// Adds the entries of the playlist file, reporting the ones that
+    /// weren't found.
+    fn import_playlist(&mut self) {
+        let base = Path::new(files::CURRENT_DIR);
+        let locations = Locations {
+            base,
+            collection: &playlist_file::collection_root(base),
+        };
+        let import = match playlist_file::import(Path::new(&self.playlist_file), &locations) {
+            Ok(import) => import,
+            Err(err) => {
+                self.status = format!("[ERROR] Can't import playlist: {err}");
+                return;
+            }
+        };
+        let count = import.entries.len();
+        for entry in import.entries {
+            self.playlist.add(entry);
+        }
+        self.status = match import.unresolved.as_slice() {
+            [] => format!("[OK] Imported {count} entries"),
+            unresolved => format!(
+                "[ERROR] Imported {count} entries, {} not found: {}",
+                unresolved.len(),
+                unresolved.join(", ")
+            ),
+        };
+        self.save_playlist();
+    }
+
+    fn export_playlist(&mut self) {
+        let base = Path::new(files::CURRENT_DIR);
+        let locations = Locations {
+            base,
+            collection: &playlist_file::collection_root(base),
+        };
+        let path = Path::new(&self.playlist_file);
+        let result = playlist_file::export(
+            path,
+            self.playlist.entries(),
+            &locations,
+            &self.song_lengths,
+        );
+        self.status = match result {
+            Ok(()) => format!("[OK] Exported to {}", path.display()),
+            Err(err) => format!("[ERROR] Can't export playlist: {err}"),
+        };
+    }
+
+    /// Entries with buttons to play, move and remove them, under the repeat
+    /// and shuffle controls and the playlist file import and export.
+    fn playlist_panel(&mut self, ui: &mut Ui) {
+        CollapsingHeader::new("📻 Radio")
+            .default_open(false)
+            .show(ui, |ui| self.radio_panel(ui));
+
+        let mut step = None;
+        ui.horizontal(|ui| {
+            ui.heading("Playlist");
+            if ui.button("⏮").on_hover_text("Previous entry").clicked() {
+                step = self.playlist.back();
+            }
+            if ui.button("⏭").on_hover_text("Next entry").clicked() {
+                step = self.playlist.advance();
+            }
+        });
+        if let Some(entry) = step {
+            self.play_entry(&entry, false);
+        }
+        let (repeat, shuffle) = (self.playlist.repeat, self.playlist.shuffle());
+        ui.horizontal(|ui| {
+            egui::ComboBox::from_id_salt("repeat")
+                .selected_text(format!("Repeat: {:?}", self.playlist.repeat))
+                .show_ui(ui, |ui| {
+                    for mode in [Repeat::Off, Repeat::One, Repeat::All] {
+                        ui.selectable_value(&mut self.playlist.repeat, mode, format!("{mode:?}"));
+                    }
+                });
+            let mut shuffle = shuffle;
+            if ui.checkbox(&mut shuffle, "Shuffle").changed() {
+                self.playlist.set_shuffle(shuffle);
+            }
+            if ui.button("Clear").clicked() {
+                self.playlist.clear();
+            }
+        });
+        let changed = repeat != self.playlist.repeat || shuffle != self.playlist.shuffle();
+        ui.horizontal(|ui| {
+            ui.add(
+                egui::TextEdit::singleline(&mut self.playlist_file)
+                    .hint_text("playlist.m3u8")
+                    .desired_width(120.0),
+            )
+            .on_hover_text("M3U, M3U8, PLS or JSON file");
+            let named = !self.playlist_file.trim().is_empty();
+            if ui.add_enabled(named, egui::Button::new("Import")).clicked() {
+                self.import_playlist();
+            }
+            if ui.add_enabled(named, egui::Button::new("Export")).clicked() {
+                self.export_playlist();
+            }
+        });
+        ui.separator();
+
+        enum Edit {
+            Play(usize),
+            Move(usize, usize),
+            Remove(usize),
+        }
+        let mut edit = None;
+        let last = self.playlist.entries().len().saturating_sub(1);
+        ScrollArea::vertical().show(ui, |ui| {
+            for (index, entry) in self.playlist.entries().iter().enumerate() {
+                ui.horizontal(|ui| {
+                    let current = self.playlist.current() == Some(index);
+                    if ui
+                        .selectable_label(current, entry.title())
+                        .on_hover_text(&entry.path)
+                        .double_clicked()
+                    {
+                        edit = Some(Edit::Play(index));
+                    }
+                    if ui.add_enabled(index > 0, egui::Button::new("⬆")).clicked() {
+                        edit = Some(Edit::Move(index, index - 1));
+                    }
+                    if ui
+                        .add_enabled(index < last, egui::Button::new("⬇"))
+                        .clicked()
+                    {
+                        edit = Some(Edit::Move(index, index + 1));
+                    }
+                    if ui.button("✖").clicked() {
+                        edit = Some(Edit::Remove(index));
+                    }
+                });
+            }
+            if !self.playlist.queue().is_empty() {
+                ui.separator();
+                ui.label("Up next:");
+                for entry in self.playlist.queue() {
+                    ui.label(entry.title());
+                }
+            }
+        });
+
+        match edit {
+            Some(Edit::Play(index)) => {
+                if let Some(entry) = self.playlist.select(index) {
+                    self.play_entry(&entry, false);
+                }
+            }
+            Some(Edit::Move(from, to)) => self.playlist.move_entry(from, to),
+            Some(Edit::Remove(index)) => {
+                self.playlist.remove(index);
+            }
+            None if !changed => return,
+            None => {}
+        }
+        self.save_playlist();
+    }
+
+    /// Measures every subtune of the loaded tune on a background thread.
+    fn scan_loudness(&mut self) {
+        let (Some(sid_file), Some(md5)) = (self.sid_file.clone(), self.md5.clone()) else {
+            return;
+        };
+        let (sender, receiver) = mpsc::channel();
+        std::thread::spawn(move || {
+            let result = loudness::scan(&sid_file, loudness::DEFAULT_SCAN_DURATION);
+            let _ = sender.send((md5, result));
+        });
+        self.scan = Some(receiver);
+        self.status = "Measuring loudness...".to_owned();
+    }
+
+    fn check_scan(&mut self) {
+        let Some(Ok((md5, result))) = self.scan.as_ref().map(Receiver::try_recv) else {
+            return;
+        };
+        self.scan = None;
+        self.loudness.tunes.insert(md5, result);
+        self.normalized = None;
+        self.status = match self.loudness.save() {
+            Ok(()) => "[OK] Loudness measured".to_owned(),
+            Err(err) => format!("[ERROR] Can't save loudness: {err}"),
+        };
+    }
+
+    /// Finds the length of every subtune of the loaded tune by loop
+    /// detection on a background thread.
+    fn detect_lengths(&mut self) {
+        let (Some(sid_file), Some(md5)) = (self.sid_file.clone(), self.md5.clone()) else {
+            return;
+        };
+        let (sender, receiver) = mpsc::channel();
+        std::thread::spawn(move || {
+            let result = loop_detection::detect(&sid_file, loop_detection::DEFAULT_MAX_LENGTH);
+            let _ = sender.send((md5, result));
+        });
+        self.length_scan = Some(receiver);
+        self.status = "Detecting song lengths...".to_owned();
+    }
+
+    fn check_length_scan(&mut self) {
+        let Some(Ok((md5, result))) = self.length_scan.as_ref().map(Receiver::try_recv) else {
+            return;
+        };
+        self.length_scan = None;
+        self.detected_lengths.tunes.insert(md5, result);
+        self.status = match self.detected_lengths.save() {
+            Ok(()) => "[OK] Song lengths detected".to_owned(),
+            Err(err) => format!("[ERROR] Can't save song lengths: {err}"),
+        };
+    }
+
+    fn length_info(&mut self, ui: &mut Ui, song: u16) {
+        let known = self
+            .md5
+            .as_ref()
+            .and_then(|md5| self.song_lengths.get_either(md5, &self.full_md5, song));
+        let detected = self.md5.as_ref().and_then(|md5| {
+            let songs = self.detected_lengths.tunes.get(md5)?;
+            songs.get(song.checked_sub(1)? as usize).copied()
+        });
+        match (known, detected) {
+            (Some(length), _) => {
+                ui.label(songlength::format_time(length));
+            }
+            (None, Some(Some(detected))) => {
+                ui.label(detected.describe());
+            }
+            (None, Some(None)) => {
+                ui.label("No loop found");
+            }
+            (None, None) if self.length_scan.is_some() => {
+                ui.spinner();
+            }
+            (None, None) if self.sid_file.is_some() => {
+                if ui.button("Detect").clicked() {
+                    self.detect_lengths();
+                }
+            }
+            (None, None) => {
+                ui.label("");
+            }
+        }
+    }
+
+    /// Star and rating buttons for the loaded tune, or `song` of it.
+    fn mark_controls(&mut self, ui: &mut Ui, song: Option<u16>) {
+        let (Some(md5), Some(path), Some(sid_file)) = (&self.md5, &self.path, &self.sid_file)
+        else {
+            ui.label("");
+            return;
+        };
+        let mut mark = self.favorites.get(md5, song);
+        let star = if mark.starred { "★" } else { "☆" };
+        let hover = match song {
+            Some(song) => format!("Star song {song}"),
+            None => "Star the tune".to_owned(),
+        };
+        let mut changed = false;
+        ui.horizontal(|ui| {
+            if ui
+                .selectable_label(mark.starred, star)
+                .on_hover_text(hover)
+                .clicked()
+            {
+                mark.starred = !mark.starred;
+                changed = true;
+            }
+            ui.separator();
+            for rating in 1..=Mark::MAX_RATING {
+                let lit = mark.rating.is_some_and(|current| rating <= current);
+                let label = if lit { "★" } else { "☆" };
+                if ui.add(egui::Button::new(label).frame(false)).clicked() {
+                    // Clicking the current rating takes it back.
+                    mark.rating = (mark.rating != Some(rating)).then_some(rating);
+                    changed = true;
+                }
+            }
+        });
+        if changed {
+            let title = format!("{} - {}", sid_file.author, sid_file.name);
+            let (md5, path) = (md5.clone(), path.clone());
+            self.favorites.set(&md5, song, &path, &title, mark);
+            self.save_favorites();
+        }
+    }
+
+    /// Saves the favorites after a change, and lists the starred ones anew.
+    fn save_favorites(&mut self) {
+        self.starred = self.favorites.starred().into();
+        if let Err(err) = self.favorites.save() {
+            self.status = format!("[ERROR] Can't save favorites: {err}");
+        }
+    }
+
+    fn tune_loudness(&self) -> Option<&TuneLoudness> {
+        self.loudness.tunes.get(self.md5.as_ref()?)
+    }
+
+    /// Sends the normalization gain whenever the song or the mode changes.
+    fn normalize(&mut self, song: u16) {
+        let normalization = self.settings.normalization;
+        if self.normalized == Some((song, normalization)) {
+            return;
+        }
+        let gain_db = self
+            .tune_loudness()
+            .and_then(|tune| tune.gain_db(song, normalization))
+            .unwrap_or(0.0);
+        self.player.send(Command::SetNormalization(gain_db as f32));
+        self.normalized = Some((song, normalization));
+    }
+
+    fn loudness_info(&mut self, ui: &mut Ui, song: u16) {
+        let measured = self.tune_loudness().and_then(|tune| tune.song(song));
+        match measured {
+            Some(loudness) => {
+                ui.label(format!(
+                    "{:.1} LUFS, peak {:.1} dBFS",
+                    loudness.lufs, loudness.peak_db
+                ));
+            }
+            None if self.scan.is_some() => {
+                ui.spinner();
+            }
+            None if self.sid_file.is_some() => {
+                if ui.button("Measure").clicked() {
+                    self.scan_loudness();
+                }
+            }
+            None => {
+                ui.label("");
+            }
+        }
+    }
+
     pub fn add_dir(&mut self, ui: &mut Ui, value: &Value) {
         if let Some(vv) = value.get("type") {
             match vv.as_str() {
@@ -87,29 +1233,178 @@ impl App {
                 }
                 Some("file") => {
                     let name = value.get("name").unwrap().as_str().unwrap();
-                    let link = ui.link(name);
-                    if link.clicked() {
-                        let path = value.get("path").unwrap().as_str().unwrap();
-                        self.status = if let Ok(()) = self.load(path) {
-                            format!("[OK] {} loaded!", name)
-                        } else {
-                            format!("[ERROR] Can't load {}!", name)
-                        }
-                    }
+                    let path = value.get("path").unwrap().as_str().unwrap();
+                    self.entry_link(ui, &Entry::new(path, None), name);
                 }
                 _ => {}
             }
         }
     }
 
+    /// A link playing `entry`, with a menu for the playlist.
+    fn entry_link(&mut self, ui: &mut Ui, entry: &Entry, label: &str) {
+        let link = ui.link(label);
+        if link.clicked() {
+            self.play_entry(entry, false);
+        }
+        link.context_menu(|ui| {
+            if ui.button("Add to playlist").clicked() {
+                self.playlist.add(entry.clone());
+                self.save_playlist();
+                ui.close();
+            }
+            if ui.button("Play next").clicked() {
+                self.playlist.enqueue(entry.clone());
+                ui.close();
+            }
+        });
+    }
+
+    /// Adds what's playing to the history: a new play whenever another
+    /// song starts, and the time listened to it. Saved as a play starts,
+    /// which is when the previous one is over, and on exit.
+    fn record_history(&mut self) {
+        if !self.player.status_is_current() {
+            return;
+        }
+        let status = self.player.status().clone();
+        let (Some(md5), Some(path), Some(sid_file)) = (&self.md5, &self.path, &self.sid_file)
+        else {
+            return;
+        };
+        if !self.history.follow(&status, md5, path, sid_file) {
+            return;
+        }
+        if let Err(err) = self.history.save() {
+            log::error!("Can't save the play history: {err}");
+        }
+        self.refresh_history();
+    }
+
+    fn refresh_history(&mut self) {
+        self.recent = self.history.recent(Self::RECENT_COUNT).into();
+        self.stats = self.history.stats();
+    }
+
+    /// Subtunes played last, most recent first.
+    fn recent_folder(&mut self, ui: &mut Ui) {
+        let recent = Rc::clone(&self.recent);
+        if recent.is_empty() {
+            return;
+        }
+        CollapsingHeader::new("🕒 Recently played")
+            .default_open(false)
+            .show(ui, |ui| {
+                for (entry, title) in recent.iter() {
+                    self.entry_link(ui, entry, title);
+                }
+            });
+    }
+
+    fn stats_window(&mut self, ctx: &Context) {
+        let mut open = self.show_stats;
+        egui::Window::new("Statistics")
+            .open(&mut open)
+            .show(ctx, |ui| {
+                let stats = &self.stats;
+                ui.label(format!(
+                    "{} plays, {:.1} hours listened",
+                    stats.plays,
+                    stats.listened.as_secs_f64() / 3600.0
+                ));
+                let tallies = [
+                    (
+                        "Most played composers",
+                        &stats.composers[..stats.composers.len().min(10)],
+                    ),
+                    ("By release year", &stats.years[..]),
+                ];
+                for (heading, tallies) in tallies {
+                    CollapsingHeader::new(heading)
+                        .default_open(true)
+                        .show(ui, |ui| {
+                            egui::Grid::new(heading).num_columns(3).show(ui, |ui| {
+                                for tally in tallies {
+                                    ui.label(&tally.name);
+                                    ui.label(format!("{} plays", tally.plays));
+                                    ui.label(songlength::format_time(tally.listened));
+                                    ui.end_row();
+                                }
+                            });
+                        });
+                }
+                ui.horizontal(|ui| {
+                    ui.text_edit_singleline(&mut self.listens_file);
+                    if ui
+                        .button("Export")
+                        .on_hover_text("Save plays of 30 s or more for ListenBrainz")
+                        .clicked()
+                    {
+                        let path = Path::new(&self.listens_file);
+                        self.status = match self.history.export_listenbrainz(path) {
+                            Ok(()) => format!("[OK] Exported to {}", path.display()),
+                            Err(err) => format!("[ERROR] Can't export the history: {err}"),
+                        };
+                    }
+                });
+            });
+        self.show_stats = open;
+    }
+
+    /// Starred tunes and subtunes, wherever they are in the collection.
+    fn favorites_folder(&mut self, ui: &mut Ui) {
+        let favorites = Rc::clone(&self.starred);
+        if favorites.is_empty() {
+            return;
+        }
+        CollapsingHeader::new("⭐ Favorites")
+            .default_open(false)
+            .show(ui, |ui| {
+                for favorite in favorites.iter() {
+                    let label = format!("{} {}", favorite.title, favorite.mark.stars());
+                    self.entry_link(ui, &favorite.entry, label.trim_end());
+                }
+            });
+    }
+
     pub fn show(&mut self, ui: &mut Ui) {
+        self.favorites_folder(ui);
+        self.recent_folder(ui);
         self.add_dir(ui, &self.json.to_owned());
     }
 }
 
+/// Checkbox turning a value on, and the value while it's on.
+fn optional_value<T: egui::emath::Numeric>(ui: &mut Ui, value: &mut Option<T>, default: T) {
+    let mut set = value.is_some();
+    if ui.checkbox(&mut set, "").changed() {
+        *value = set.then_some(default);
+    }
+    if let Some(value) = value {
+        ui.add(egui::DragValue::new(value));
+    }
+}
+
+impl Drop for App {
+    fn drop(&mut self) {
+        if let Err(err) = self.history.save() {
+            log::error!("Can't save the play history: {err}");
+        }
+    }
+}
+
 impl eframe::App for App {
     fn update(&mut self, ctx: &Context, _frame: &mut Frame) {
         ctx.request_repaint();
+        self.check_output();
+        self.check_scan();
+        self.check_length_scan();
+        self.check_radio_pick();
+        self.check_snapshot();
+        self.follow_playlist();
+        self.record_history();
+        let song = self.player.status().song;
+        self.normalize(song);
         egui::TopBottomPanel::top("top").show(ctx, |ui| {
             egui::SidePanel::right("right_panel").show_inside(ui, |ui| {
                 let length = if let Some(file) = &self.sid_file {
@@ -182,6 +1477,19 @@ impl eframe::App for App {
                 ui.label("Number of songs:");
                 ui.label(songs);
                 ui.end_row();
+                let song = self.player.status().song;
+                ui.label("Length:");
+                self.length_info(ui, song);
+                ui.end_row();
+                ui.label("Tune:");
+                self.mark_controls(ui, None);
+                ui.end_row();
+                ui.label(format!("Song {song}:"));
+                self.mark_controls(ui, Some(song));
+                ui.end_row();
+                ui.label("Loudness:");
+                self.loudness_info(ui, song);
+                ui.end_row();
             });
         });
         egui::TopBottomPanel::top("topxxx")
@@ -189,32 +1497,78 @@ impl eframe::App for App {
             .show(ctx, |ui| {
                 ui.horizontal_centered(|ui| {
                     if ui.button("◀◀").clicked() {
-                        self.sid_player.lock().prev()
+                        self.player.send(Command::Prev)
                     };
                     if ui.button("▶").clicked() {
-                        self.sid_player.lock().play()
+                        self.player.send(Command::Play)
                     };
                     if ui.button("⏸").clicked() {
-                        self.sid_player.lock().pause()
+                        self.player.send(Command::Pause)
                     };
                     if ui.button("■").clicked() {
-                        self.sid_player.lock().stop()
+                        self.player.send(Command::Stop)
                     };
                     if ui.button("▶▶").clicked() {
-                        self.sid_player.lock().next()
+                        self.player.send(Command::Next)
+                    };
+                    if ui
+                        .add_enabled(self.path.is_some(), egui::Button::new("➕"))
+                        .on_hover_text("Add this song to the playlist")
+                        .clicked()
+                    {
+                        let song = self.player.status().song;
+                        if let Some(path) = self.path.clone() {
+                            self.playlist.add(Entry::new(path, Some(song)));
+                            self.save_playlist();
+                        }
+                    };
+                    let loaded = self.md5.is_some();
+                    if ui
+                        .add_enabled(loaded, egui::Button::new("💾"))
+                        .on_hover_text("Save the current state")
+                        .clicked()
+                    {
+                        self.save_snapshot();
+                    };
+                    if ui
+                        .add_enabled(loaded, egui::Button::new("📂"))
+                        .on_hover_text("Restore the saved state")
+                        .clicked()
+                    {
+                        self.load_snapshot();
+                    };
+                    if ui.button("⚙").clicked() {
+                        self.show_settings = !self.show_settings;
+                        if self.show_settings {
+                            self.refresh_devices();
+                        }
                     };
-                    // let volume_slider = ui.add(
-                    //     eframe::egui::Slider::new(&mut self.volume, (0.0 as f32)..=(1.2 as f32))
-                    //         .logarithmic(false)
-                    //         .show_value(false)
-                    //         .step_by(0.01),
-                    // );
+                    if ui.button("ℹ").clicked() {
+                        self.show_diagnostics = !self.show_diagnostics;
+                    };
+                    if ui.button("📊").on_hover_text("Statistics").clicked() {
+                        self.show_stats = !self.show_stats;
+                    };
+                    self.position_bar(ui);
+                    self.loop_controls(ui);
+                    let level = self.player.status().level;
+                    ui.add(egui::ProgressBar::new(level).desired_width(60.0));
+                    self.speed_control(ui);
+                    self.volume_control(ui);
                 });
             });
+        self.settings_window(ctx);
+        self.diagnostics_window(ctx);
+        self.stats_window(ctx);
+
         egui::TopBottomPanel::bottom("status").show(ctx, |ui| {
             ui.label(&self.status);
         });
 
+        egui::SidePanel::right("playlist")
+            .default_width(260.0)
+            .show(ctx, |ui| self.playlist_panel(ui));
+
         egui::CentralPanel::default().show(ctx, |ui| {
             ScrollArea::vertical().auto_shrink(false).show(ui, |ui| {
                 self.show(ui);
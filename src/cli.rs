@@ -1,51 +1,971 @@
-mod output;
-mod sid_player;
-
-use anyhow::Result;
-use cpal::traits::DeviceTrait;
+use anyhow::{bail, Context, Result};
+use crossterm::{
+    event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers},
+    terminal,
+};
 use inline_colorization::*;
-use output::Output;
-use rb::{SpscRb, RB};
 use sid_file::SidFile;
-use sid_player::SidPlayer;
-use std::time::{Duration, Instant};
+use std::{
+    io::IsTerminal,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+use vicious::{
+    ab_loop::{LoopRegion, LoopStore},
+    favorites::Favorites,
+    history::History,
+    hvsc::{self, SidModel},
+    loop_detection::{self, LengthCache},
+    loudness::{self, LoudnessCache, Normalization},
+    output::{self, DeviceOutput, Output, OutputEvent},
+    player_thread::{Command, PlayerThread, Status},
+    playlist::{self, Entry, Playlist, Repeat},
+    playlist_file::{self, Format, Locations},
+    radio::{Radio, RadioFilter},
+    settings::Settings,
+    sid_player::SidPlayer,
+    silence::{SilenceAction, SilenceDetection},
+    sink::{Pipeline, SinkConfig},
+    snapshot::Snapshot,
+    songlength::{self, SongLengths},
+    transition::TransitionMode,
+    volume::Volume,
+};
+
+// Debug builds abort when the audio callback allocates, see
+// `vicious::output::Output`.
+#[cfg(debug_assertions)]
+#[global_allocator]
+static ALLOCATOR: assert_no_alloc::AllocDisabler = assert_no_alloc::AllocDisabler;
+
+/// Prints a line to stderr that also ends up in the first column while the
+/// terminal is in raw mode, replacing the position line.
+macro_rules! report {
+    ($($arg:tt)*) => {
+        eprint!("\r\x1b[K{}\r\n", format_args!($($arg)*))
+    };
+}
+
+/// How far the left and right keys seek.
+const SEEK_STEP: Duration = Duration::from_secs(10);
+
+#[derive(Default)]
+struct Args {
+    /// Files to play, or a subcommand followed by its arguments.
+    positional: Vec<String>,
+    list_devices: bool,
+    sink: SinkConfig,
+    duration: Option<Duration>,
+    host: Option<String>,
+    device: Option<String>,
+    latency_ms: Option<u32>,
+    /// Tempo in percent.
+    speed: Option<u32>,
+    repeat: Repeat,
+    shuffle: bool,
+    /// Transition overrides.
+    transition: Option<TransitionMode>,
+    fade_ms: Option<u32>,
+    /// Silence detection overrides.
+    silence_ms: Option<u32>,
+    silence_db: Option<f32>,
+    silence_action: Option<SilenceAction>,
+    silence_with_dc: bool,
+    /// HVSC root that playlist paths may be relative to.
+    collection: Option<PathBuf>,
+    /// Radio filter overrides.
+    composer: Option<String>,
+    years: Option<(Option<u16>, Option<u16>)>,
+    model: Option<SidModel>,
+    not_played_days: Option<u32>,
+    verbose: bool,
+}
+
+impl Args {
+    fn parse() -> Result<Self> {
+        let mut args = Self::default();
+        let mut iter = std::env::args().skip(1);
+        while let Some(arg) = iter.next() {
+            match arg.as_str() {
+                "--list-devices" => args.list_devices = true,
+                "--null" => args.sink = SinkConfig::Null { realtime: true },
+                "--fast" => args.sink = SinkConfig::Null { realtime: false },
+                "--stdout" => args.sink = SinkConfig::Stdout,
+                "--wav" => {
+                    let path = iter.next().context("--wav needs a file name")?;
+                    args.sink = SinkConfig::Wav(PathBuf::from(path));
+                }
+                "--duration" => {
+                    let seconds: f64 = iter.next().context("--duration needs seconds")?.parse()?;
+                    let duration = Duration::try_from_secs_f64(seconds)
+                        .context("--duration needs a non-negative number of seconds")?;
+                    args.duration = Some(duration);
+                }
+                "--host" => args.host = Some(iter.next().context("--host needs a name")?),
+                "--device" => args.device = Some(iter.next().context("--device needs a name")?),
+                "--latency" => {
+                    let ms = iter.next().context("--latency needs milliseconds")?;
+                    args.latency_ms = Some(ms.parse()?);
+                }
+                "--speed" => {
+                    let percent = iter.next().context("--speed needs a percentage")?;
+                    args.speed = Some(percent.trim_end_matches('%').parse()?);
+                }
+                "--repeat" => {
+                    args.repeat = match iter.next().as_deref() {
+                        Some("off") => Repeat::Off,
+                        Some("one") => Repeat::One,
+                        Some("all") => Repeat::All,
+                        _ => bail!("--repeat needs off, one or all"),
+                    }
+                }
+                "--shuffle" => args.shuffle = true,
+                "--transition" => {
+                    args.transition = match iter.next().as_deref() {
+                        Some("cut") => Some(TransitionMode::Cut),
+                        Some("gapless") => Some(TransitionMode::Gapless),
+                        Some("fade") => Some(TransitionMode::Fade),
+                        Some("crossfade") => Some(TransitionMode::Crossfade),
+                        _ => bail!("--transition needs cut, gapless, fade or crossfade"),
+                    }
+                }
+                "--fade" => {
+                    let ms = iter.next().context("--fade needs milliseconds")?;
+                    args.fade_ms = Some(ms.parse()?);
+                }
+                "--skip-silence" => {
+                    let ms = iter.next().context("--skip-silence needs milliseconds")?;
+                    args.silence_ms = Some(ms.parse()?);
+                }
+                "--silence-threshold" => {
+                    let db = iter.next().context("--silence-threshold needs dB")?;
+                    args.silence_db = Some(db.parse()?);
+                }
+                "--skip-silence-to" => {
+                    args.silence_action = match iter.next().as_deref() {
+                        Some("song") => Some(SilenceAction::NextSong),
+                        Some("tune") => Some(SilenceAction::NextTune),
+                        _ => bail!("--skip-silence-to needs song or tune"),
+                    }
+                }
+                "--silence-with-dc" => args.silence_with_dc = true,
+                "--hvsc" => {
+                    let dir = iter.next().context("--hvsc needs a directory")?;
+                    args.collection = Some(PathBuf::from(dir));
+                }
+                "--composer" => {
+                    args.composer = Some(iter.next().context("--composer needs a name")?)
+                }
+                "--years" => {
+                    let years = iter.next().context("--years needs FROM-TO")?;
+                    let (from, to) = years.split_once('-').unwrap_or((&years, &years));
+                    let year = |year: &str| -> Result<Option<u16>> {
+                        Ok(match year.trim() {
+                            "" => None,
+                            year => Some(year.parse()?),
+                        })
+                    };
+                    args.years = Some((year(from)?, year(to)?));
+                }
+                "--model" => {
+                    args.model = match iter.next().as_deref() {
+                        Some("6581") => Some(SidModel::Mos6581),
+                        Some("8580") => Some(SidModel::Mos8580),
+                        _ => bail!("--model needs 6581 or 8580"),
+                    }
+                }
+                "--not-played" => {
+                    let days = iter.next().context("--not-played needs days")?;
+                    args.not_played_days = Some(days.parse()?);
+                }
+                "-v" | "--verbose" => args.verbose = true,
+                flag if flag.starts_with("--") => bail!("unknown option: {flag}"),
+                _ => args.positional.push(arg),
+            }
+        }
+        Ok(args)
+    }
+}
 
 fn main() -> Result<()> {
     pretty_env_logger::init();
-    let filename = std::env::args().nth(1).unwrap_or("".to_string());
-    let path = std::path::Path::new(&filename);
-    let data = std::fs::read(path)?;
+    let args = Args::parse()?;
+    if args.list_devices {
+        return list_devices();
+    }
+    match args.positional.as_slice() {
+        [command, paths @ ..] if command == "loudness" => {
+            return scan_loudness(paths, args.duration)
+        }
+        [command, dirs @ ..] if command == "lengths" => return detect_lengths(dirs, args.duration),
+        [command, files @ ..] if command == "hash" => return print_hashes(files),
+        [command] if command == "history" => return print_stats(),
+        [command, export, output] if command == "history" && export == "export" => {
+            return export_history(output)
+        }
+        [command] if command == "favorites" => return list_favorites(),
+        [command, list] if command == "favorites" && list == "list" => return list_favorites(),
+        [command, output, files @ ..] if command == "export" => {
+            return export_playlist(&args, output, files)
+        }
+        _ => {}
+    }
+
+    let mut settings = Settings::load();
+    if let Some(host) = &args.host {
+        settings.output.host = Some(host.clone());
+    }
+    if let Some(device) = &args.device {
+        settings.output.device = Some(device.clone());
+    }
+    if let Some(latency_ms) = args.latency_ms {
+        settings.output.latency_ms = latency_ms;
+    }
+    if args.host.is_some() || args.device.is_some() || args.latency_ms.is_some() {
+        settings.save()?;
+    }
+
+    let (playlist, radio, follow) = match args.positional.as_slice() {
+        [command, dirs @ ..] if command == "radio" => {
+            let radio = tune_radio(&args, dirs, settings.radio.clone())?;
+            (Playlist::default(), Some(radio), true)
+        }
+        [command, play] if command == "favorites" && play == "play" => {
+            (favorites_playlist(&args)?, None, true)
+        }
+        files => {
+            let (playlist, follow) = playlist_from_args(&args, files)?;
+            (playlist, None, follow)
+        }
+    };
+    let mut source = Source {
+        playlist,
+        radio,
+        loudness: LoudnessCache::load(),
+        normalization: settings.normalization,
+    };
+    let pipeline = Pipeline::build(&args.sink, &settings.output)?;
+    let mut sid_player = SidPlayer::new(pipeline.sink);
+    sid_player.set_volume(settings.volume);
+    sid_player.set_speed(args.speed.unwrap_or(SidPlayer::NORMAL_SPEED));
+    let mut transition = settings.transition;
+    transition.mode = args.transition.unwrap_or(transition.mode);
+    transition.duration_ms = args.fade_ms.unwrap_or(transition.duration_ms);
+    sid_player.set_transition(transition);
+    let silence = silence_detection(&args, settings.silence);
+    sid_player.set_silence_detection(silence);
+    let mut player = PlayerThread::spawn(sid_player, args.duration);
+    let mut history = History::load();
+    let Some(mut tune) = source.play(&player, &history, false, false) else {
+        player.send(Command::Quit);
+        bail!("nothing to play");
+    };
+
+    if args.sink != SinkConfig::Stdout {
+        print_info(&tune.sid_file);
+    }
+    if let Some(output) = pipeline.device.as_ref().and_then(|device| device.output()) {
+        print_sound_info(output);
+    }
+
+    let mut device = pipeline.device;
+    let initial_volume = settings.volume;
+    let song_lengths = SongLengths::find(Path::new(&tune.path))
+        .and_then(|path| SongLengths::load(&path).ok())
+        .unwrap_or_default();
+    let detected_lengths = LengthCache::load();
+    let song_length = |tune: &Tune, song| {
+        song_lengths
+            .get_either(&tune.md5, &tune.full_md5, song)
+            .or_else(|| detected_lengths.get(&tune.md5, song))
+    };
+    let mut loops = Loops {
+        store: LoopStore::load(),
+        start: None,
+    };
+    let keys = Keys::enable();
+    let mut last_report = Instant::now();
+    while !player.is_finished() {
+        let mut step = None;
+        let mut automatic = false;
+        match &keys {
+            Some(keys) => {
+                for key in keys.poll(Duration::from_millis(100))? {
+                    match key.code {
+                        KeyCode::PageDown => step = Some(false),
+                        KeyCode::PageUp => step = Some(true),
+                        _ => handle_key(key, &mut player, &mut settings, &tune.md5, &mut loops),
+                    }
+                }
+                let status = player.status();
+                print_position(status, song_length(&tune, status.song));
+            }
+            None => std::thread::sleep(Duration::from_millis(100)),
+        }
+
+        let mut silent = false;
+        if player.status_is_current() {
+            let status = player.status().clone();
+            if history.follow(&status, &tune.md5, &tune.path, &tune.sid_file) {
+                save_history(&history);
+            }
+            silent = silence.triggered(&status);
+            if silent && silence.action == SilenceAction::NextSong && status.song < status.songs {
+                report!("Skipping silence");
+                player.send(Command::SelectSong(status.song + 1));
+            } else if silent
+                || follow && playlist::song_over(&status, song_length(&tune, status.song))
+            {
+                automatic = step.is_none();
+                step = step.or(Some(false));
+            }
+        }
+        if let Some(back) = step {
+            match source.play(&player, &history, back, automatic) {
+                Some(next) => {
+                    tune = next;
+                    history.finish();
+                }
+                // Silence at the end of the last tune ends playback as well.
+                None if follow || silent => player.send(Command::Quit),
+                None => {}
+            }
+        }
+
+        let Some(device) = &mut device else {
+            continue;
+        };
+        if args.verbose && last_report.elapsed() >= Duration::from_secs(1) {
+            print_buffer_stats(device);
+            last_report = Instant::now();
+        }
+
+        while let Some(event) = device.poll() {
+            match event {
+                OutputEvent::Error(err) => {
+                    report!("{color_red}Audio output error: {err}{color_reset}")
+                }
+                OutputEvent::Reconnected => {
+                    if let Some(output) = device.output() {
+                        print_sound_info(output);
+                    }
+                }
+                OutputEvent::ReconnectFailed(err, delay) => report!(
+                    "{color_red}Can't reopen output: {err}, retrying in {delay:?}{color_reset}"
+                ),
+            }
+        }
+    }
+    if keys.is_some() {
+        eprint!("\r\n");
+    }
+    drop(keys);
+    save_history(&history);
+    if settings.volume != initial_volume {
+        settings.save()?;
+    }
+
+    player.join()
+}
+
+/// Playback keys: space pauses, left and right seek, home restarts the
+/// song, `s` and `l` save and restore a snapshot, `a`, `b`, `x` and `r`
+/// control the A/B loop, `+`/`-` change the volume, `m` mutes, `q` quits.
+fn handle_key(
+    key: KeyEvent,
+    player: &mut PlayerThread,
+    settings: &mut Settings,
+    md5: &str,
+    loops: &mut Loops,
+) {
+    let volume = &mut settings.volume;
+    let position = player.status().position;
+    match key.code {
+        KeyCode::Char(key @ ('a' | 'b' | 'x' | 'r')) => return loops.handle_key(key, player, md5),
+        KeyCode::Char('s') => return save_snapshot(player, md5),
+        KeyCode::Char('l') => return load_snapshot(player, md5),
+        KeyCode::Left => {
+            return player.send(Command::Seek(position.saturating_sub(SEEK_STEP)));
+        }
+        KeyCode::Right => return player.send(Command::Seek(position + SEEK_STEP)),
+        KeyCode::Home => return player.send(Command::Seek(Duration::ZERO)),
+        KeyCode::Char(' ') | KeyCode::Char('p') => {
+            let command = if player.status().playing {
+                Command::Pause
+            } else {
+                Command::Play
+            };
+            return player.send(command);
+        }
+        KeyCode::Char('+') | KeyCode::Char('=') | KeyCode::Up => volume.adjust(Volume::STEP_DB),
+        KeyCode::Char('-') | KeyCode::Down => volume.adjust(-Volume::STEP_DB),
+        KeyCode::Char('m') => volume.muted = !volume.muted,
+        KeyCode::Char('q') | KeyCode::Esc => return player.send(Command::Quit),
+        KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+            return player.send(Command::Quit)
+        }
+        _ => return,
+    }
+    player.send(Command::SetVolume(*volume));
+    if volume.muted {
+        report!("Volume: {:.0} dB (muted)", volume.db);
+    } else {
+        report!("Volume: {:.0} dB", volume.db);
+    }
+}
+
+/// A loaded SID file, its path and HVSC MD5.
+struct Tune {
+    sid_file: SidFile,
+    path: String,
+    md5: String,
+    full_md5: String,
+}
+
+/// Builds the playlist from the files and JSON playlists on the command
+/// line. Playback only moves on from song to song (and ends) when there
+/// is more than one file, a playlist or a repeat mode.
+fn playlist_from_args(args: &Args, files: &[String]) -> Result<(Playlist, bool)> {
+    let collection = args
+        .collection
+        .clone()
+        .unwrap_or_else(|| playlist_file::collection_root(Path::new(".")));
+    let locations = Locations {
+        base: Path::new("."),
+        collection: &collection,
+    };
+    let mut playlist = Playlist::default();
+    // Rendering offline never ends on its own, so even a single song stops
+    // at its length there.
+    let offline = matches!(
+        args.sink,
+        SinkConfig::Wav(_) | SinkConfig::Null { realtime: false }
+    );
+    let mut follow = files.len() > 1 || args.repeat != Repeat::Off || args.shuffle || offline;
+    for file in files {
+        let path = Path::new(file);
+        if Format::of(path).is_none() {
+            playlist.add(Entry::new(file.clone(), None));
+            continue;
+        }
+        let import = playlist_file::import(path, &locations)?;
+        for unresolved in &import.unresolved {
+            report!("{color_red}Not found: {unresolved}{color_reset}");
+        }
+        for entry in import.entries {
+            playlist.add(entry);
+        }
+        follow = true;
+    }
+    playlist.repeat = args.repeat;
+    playlist.set_shuffle(args.shuffle);
+    Ok((playlist, follow))
+}
+
+fn save_history(history: &History) {
+    if let Err(err) = history.save() {
+        report!("{color_red}Can't save the play history: {err}{color_reset}");
+    }
+}
+
+/// Prints the totals of the play history, the most played composers and
+/// the plays by release year.
+fn print_stats() -> Result<()> {
+    let stats = History::load().stats();
+    println!(
+        "{} plays, {:.1} hours listened",
+        stats.plays,
+        stats.listened.as_secs_f64() / 3600.0
+    );
+    let tallies = [
+        (
+            "Most played composers",
+            &stats.composers[..stats.composers.len().min(10)],
+        ),
+        ("By release year", &stats.years[..]),
+    ];
+    for (heading, tallies) in tallies {
+        println!("{color_yellow}{heading}{color_reset}");
+        for tally in tallies {
+            println!(
+                "  {color_cyan}{:<32}{color_reset} {:>5} plays {color_green}{:>8}{color_reset}",
+                tally.name,
+                tally.plays,
+                songlength::format_time(tally.listened)
+            );
+        }
+    }
+    Ok(())
+}
+
+fn export_history(output: &str) -> Result<()> {
+    let history = History::load();
+    history.export_listenbrainz(Path::new(output))?;
+    println!("Wrote the play history to {output}");
+    Ok(())
+}
+
+fn list_favorites() -> Result<()> {
+    for favorite in Favorites::load().starred() {
+        println!(
+            "{color_yellow}{}{color_reset} {color_green}{}{color_reset}",
+            favorite.title,
+            favorite.mark.stars()
+        );
+        println!("  {}", favorite.entry.path);
+    }
+    Ok(())
+}
+
+/// The starred tunes and subtunes as a playlist, in the order they are
+/// listed.
+fn favorites_playlist(args: &Args) -> Result<Playlist> {
+    let mut playlist = Playlist::default();
+    for favorite in Favorites::load().starred() {
+        playlist.add(favorite.entry);
+    }
+    if playlist.is_empty() {
+        bail!("no favorites yet, star tunes in the GUI");
+    }
+    playlist.repeat = args.repeat;
+    playlist.set_shuffle(args.shuffle);
+    Ok(playlist)
+}
+
+/// Writes the files and playlists on the command line into one playlist
+/// file, in the format its extension names.
+fn export_playlist(args: &Args, output: &str, files: &[String]) -> Result<()> {
+    let (playlist, _) = playlist_from_args(args, files)?;
+    let lengths = files
+        .iter()
+        .find_map(|file| SongLengths::find(Path::new(file)))
+        .and_then(|path| SongLengths::load(&path).ok())
+        .unwrap_or_default();
+    let locations = Locations {
+        base: Path::new("."),
+        collection: Path::new("."),
+    };
+    playlist_file::export(Path::new(output), playlist.entries(), &locations, &lengths)?;
+    println!("Wrote {} entries to {output}", playlist.entries().len());
+    Ok(())
+}
+
+/// Where the tunes come from: the playlist, fed by the radio when on.
+struct Source {
+    playlist: Playlist,
+    radio: Option<Radio>,
+    loudness: LoudnessCache,
+    normalization: Normalization,
+}
+
+impl Source {
+    /// Loads the next entry, or the previous one going `back`, skipping
+    /// entries that can't be played. `None` once the playlist runs out.
+    /// Only `automatic` moves, at the end of a song, use the transition.
+    /// The radio skips tunes by when the `history` last played them.
+    fn play(
+        &mut self,
+        player: &PlayerThread,
+        history: &History,
+        back: bool,
+        automatic: bool,
+    ) -> Option<Tune> {
+        // Gives up after a round without a playable entry.
+        for _ in 0..=self.playlist.entries().len() + self.playlist.queue().len() + 1 {
+            if let Some(radio) = self.radio.as_mut().filter(|_| !back) {
+                let read = |path: &str| std::fs::read(path).ok();
+                match radio.pick(read, &history.last_played()) {
+                    Some(entry) => self.playlist.enqueue(entry),
+                    None => report!("{color_red}No tune matches the radio filter{color_reset}"),
+                }
+            }
+            let entry = if back {
+                self.playlist.back()
+            } else {
+                self.playlist.advance()
+            }?;
+            match load_entry(
+                player,
+                &entry,
+                &self.loudness,
+                self.normalization,
+                automatic,
+            ) {
+                Ok(tune) => {
+                    report!(
+                        "Playing {}: {} by {}",
+                        entry.title(),
+                        tune.sid_file.name,
+                        tune.sid_file.author
+                    );
+                    return Some(tune);
+                }
+                Err(err) => report!("{color_red}Can't play {}: {err}{color_reset}", entry.path),
+            }
+        }
+        None
+    }
+}
+
+/// Silence detection from the settings, overridden by the command line.
+/// A duration on the command line turns it on.
+fn silence_detection(args: &Args, mut silence: SilenceDetection) -> SilenceDetection {
+    if let Some(ms) = args.silence_ms {
+        silence.enabled = true;
+        silence.duration_ms = ms;
+    }
+    silence.threshold_db = args.silence_db.unwrap_or(silence.threshold_db);
+    silence.action = args.silence_action.unwrap_or(silence.action);
+    if args.silence_with_dc {
+        silence.ignore_dc = false;
+    }
+    silence
+}
+
+/// A radio over the SID files in `dirs` (`./C64Music` by default), with
+/// the filter from the settings overridden by the command line.
+fn tune_radio(args: &Args, dirs: &[String], mut filter: RadioFilter) -> Result<Radio> {
+    let mut files = Vec::new();
+    if dirs.is_empty() {
+        collect_sid_files(&playlist_file::collection_root(Path::new(".")), &mut files)?;
+    }
+    for dir in dirs {
+        collect_sid_files(Path::new(dir), &mut files)?;
+    }
+    if files.is_empty() {
+        bail!("no SID files to pick from");
+    }
+    if let Some(composer) = &args.composer {
+        filter.composer = composer.clone();
+    }
+    if let Some((from, to)) = args.years {
+        filter.from_year = from;
+        filter.to_year = to;
+    }
+    if args.model.is_some() {
+        filter.model = args.model;
+    }
+    if args.not_played_days.is_some() {
+        filter.not_played_days = args.not_played_days;
+    }
+    let paths = files
+        .iter()
+        .map(|file| file.to_string_lossy().into_owned())
+        .collect();
+    Ok(Radio::new(paths, filter))
+}
+
+fn load_entry(
+    player: &PlayerThread,
+    entry: &Entry,
+    loudness: &LoudnessCache,
+    normalization: Normalization,
+    transition: bool,
+) -> Result<Tune> {
+    let path = Path::new(&entry.path);
+    let data = std::fs::read(path).with_context(|| format!("can't read {}", path.display()))?;
     let sid_file = SidFile::parse(&data)?;
-    let buffer: SpscRb<i16> = SpscRb::new(44100 * 2);
-    let output = Output::new(buffer.consumer())?;
-    let mut sid_player = SidPlayer::new(buffer.producer(), output.sample_rate());
-    let mut last_step = Instant::now();
-
-    sid_player.load_data(
-        &sid_file.data,
-        sid_file.real_load_address,
-        sid_file.init_address,
-        sid_file.play_address,
-        sid_file.songs,
-        sid_file.start_song,
+    let md5 = hvsc::md5(&data).unwrap_or_default();
+    let full_md5 = hvsc::full_md5(&data);
+    let song = entry.song.unwrap_or(sid_file.start_song);
+    let gain_db = loudness
+        .tunes
+        .get(&md5)
+        .and_then(|tune| tune.gain_db(song, normalization));
+
+    if transition {
+        player.send(Command::Transition(Box::new(sid_file.clone()), entry.song));
+    } else {
+        player.send(Command::Load(Box::new(sid_file.clone())));
+        if entry.song.is_some() {
+            player.send(Command::SelectSong(song));
+        }
+    }
+    player.send(Command::SetNormalization(gain_db.unwrap_or(0.0) as f32));
+    Ok(Tune {
+        sid_file,
+        path: entry.path.clone(),
+        md5,
+        full_md5,
+    })
+}
+
+/// A/B loop keys: `a` marks A, `b` marks B and starts looping, `x` stops
+/// looping and `r` loops the region saved for the song again.
+struct Loops {
+    store: LoopStore,
+    /// A point marked with no B yet.
+    start: Option<Duration>,
+}
+
+impl Loops {
+    fn handle_key(&mut self, key: char, player: &mut PlayerThread, md5: &str) {
+        let Status { position, song, .. } = *player.status();
+        match key {
+            'a' => {
+                self.start = Some(position);
+                report!("Loop from {}", songlength::format_time(position));
+            }
+            'b' => {
+                let Some(region) = self
+                    .start
+                    .and_then(|start| LoopRegion::new(start, position))
+                else {
+                    return report!("Mark A before B first");
+                };
+                self.start = None;
+                player.send(Command::SetLoop(Some(region)));
+                self.store.set(md5, song, Some(region));
+                if let Err(err) = self.store.save() {
+                    report!("{color_red}Can't save loop: {err}{color_reset}");
+                }
+                print_loop(region);
+            }
+            'x' => {
+                self.start = None;
+                player.send(Command::SetLoop(None));
+                report!("Loop off");
+            }
+            'r' => match self.store.get(md5, song) {
+                Some(region) => {
+                    player.send(Command::SetLoop(Some(region)));
+                    print_loop(region);
+                }
+                None => report!("No loop saved for song {song}"),
+            },
+            _ => {}
+        }
+    }
+}
+
+fn print_loop(region: LoopRegion) {
+    report!(
+        "Looping {} to {}",
+        songlength::format_time(region.start),
+        songlength::format_time(region.end)
+    );
+}
+
+fn save_snapshot(player: &PlayerThread, md5: &str) {
+    let (sender, receiver) = std::sync::mpsc::channel();
+    player.send(Command::Snapshot(sender));
+    let Ok(snapshot) = receiver.recv_timeout(Duration::from_secs(1)) else {
+        return;
+    };
+    match snapshot.save(&Snapshot::quick_save_path(md5)) {
+        Ok(()) => report!(
+            "Saved song {} at {}",
+            snapshot.song(),
+            songlength::format_time(snapshot.position())
+        ),
+        Err(err) => report!("{color_red}Can't save snapshot: {err}{color_reset}"),
+    }
+}
+
+fn load_snapshot(player: &PlayerThread, md5: &str) {
+    match Snapshot::load(&Snapshot::quick_save_path(md5)) {
+        Ok(snapshot) => {
+            report!(
+                "Restored song {} at {}",
+                snapshot.song(),
+                songlength::format_time(snapshot.position())
+            );
+            player.send(Command::Restore(Box::new(snapshot)));
+        }
+        Err(err) => report!("{color_red}Can't load snapshot: {err}{color_reset}"),
+    }
+}
+
+/// Rewrites the current terminal line with the song and elapsed time.
+fn print_position(status: &Status, length: Option<Duration>) {
+    let mut line = format!(
+        "Song {}/{}  {}",
+        status.song,
+        status.songs,
+        songlength::format_time(status.position)
     );
-    sid_player.play();
+    if let Some(length) = length {
+        line += &format!(" / {}", songlength::format_time(length));
+    }
+    if !status.playing {
+        line += "  (paused)";
+    }
+    eprint!("\r{line}\x1b[K");
+}
 
-    print_info(&sid_file);
-    print_sound_info(&output)?;
+/// Raw terminal mode for reading single key presses while playing,
+/// restored on drop. Only used when stdin is a terminal.
+struct Keys;
 
-    let test_tread = std::thread::spawn(move || loop {
-        if last_step.elapsed() < Duration::from_millis(20) {
+impl Keys {
+    fn enable() -> Option<Self> {
+        if !std::io::stdin().is_terminal() {
+            return None;
+        }
+        terminal::enable_raw_mode().ok()?;
+        Some(Self)
+    }
+
+    /// Waits up to `timeout` for key presses.
+    fn poll(&self, timeout: Duration) -> Result<Vec<KeyEvent>> {
+        let mut keys = Vec::new();
+        let mut timeout = timeout;
+        while event::poll(timeout)? {
+            match event::read()? {
+                Event::Key(key) if key.kind == KeyEventKind::Press => keys.push(key),
+                _ => {}
+            }
+            timeout = Duration::ZERO;
+        }
+        Ok(keys)
+    }
+}
+
+impl Drop for Keys {
+    fn drop(&mut self) {
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+/// Measures every subtune of the given files and of the SID files below the
+/// given directories, adding the results to the loudness cache.
+fn scan_loudness(paths: &[String], duration: Option<Duration>) -> Result<()> {
+    let duration = duration.unwrap_or(loudness::DEFAULT_SCAN_DURATION);
+    let mut files = Vec::new();
+    for path in paths {
+        collect_sid_files(Path::new(path), &mut files)?;
+    }
+
+    let mut cache = LoudnessCache::load();
+    for file in files {
+        let data = std::fs::read(&file)?;
+        let (Ok(sid_file), Ok(md5)) = (SidFile::parse(&data), hvsc::md5(&data)) else {
+            eprintln!("{color_red}Not a SID file: {}{color_reset}", file.display());
             continue;
+        };
+        if !cache.tunes.contains_key(&md5) {
+            cache
+                .tunes
+                .insert(md5.clone(), loudness::scan(&sid_file, duration));
+            cache.save()?;
         }
+        println!("{color_yellow}{}{color_reset}", file.display());
+        for (song, measured) in cache.tunes[&md5].songs.iter().enumerate() {
+            println!(
+                "{color_cyan}{:>3}: {color_green}{:6.1} LUFS {color_blue}peak {:5.1} dBFS{color_reset}",
+                song + 1,
+                measured.lufs,
+                measured.peak_db
+            );
+        }
+    }
+    Ok(())
+}
 
-        last_step = Instant::now();
+/// Prints both HVSC fingerprints of every file: the old MD5 tunes are
+/// known by here, and the full file MD5 of HVSC 68 and later.
+fn print_hashes(files: &[String]) -> Result<()> {
+    for file in files {
+        let data = std::fs::read(file).with_context(|| format!("can't read {file}"))?;
+        match hvsc::md5(&data) {
+            Ok(md5) => {
+                println!("{color_yellow}{file}{color_reset}");
+                println!("  old:  {md5}");
+                println!("  full: {}", hvsc::full_md5(&data));
+            }
+            Err(_) => eprintln!("{color_red}Not a SID file: {file}{color_reset}"),
+        }
+    }
+    Ok(())
+}
 
-        sid_player.step();
-    });
+/// Finds the song lengths of the SID files in `dirs` by loop detection,
+/// following each subtune for at most `max_length`, and writes them to
+/// stdout in `Songlengths.md5` format. Subtunes that don't repeat in time
+/// get the default length.
+fn detect_lengths(dirs: &[String], max_length: Option<Duration>) -> Result<()> {
+    let max_length = max_length.unwrap_or(loop_detection::DEFAULT_MAX_LENGTH);
+    let mut cache = LengthCache::load();
+    println!("[Database]");
+    for dir in dirs {
+        let dir = Path::new(dir);
+        let mut files = Vec::new();
+        collect_sid_files(dir, &mut files)?;
+        for file in files {
+            let data = std::fs::read(&file)?;
+            let (Ok(sid_file), Ok(md5)) = (SidFile::parse(&data), hvsc::md5(&data)) else {
+                eprintln!("{color_red}Not a SID file: {}{color_reset}", file.display());
+                continue;
+            };
+            if !cache.tunes.contains_key(&md5) {
+                let detected = loop_detection::detect(&sid_file, max_length);
+                cache.tunes.insert(md5.clone(), detected);
+                cache.save()?;
+            }
+            eprintln!("{color_yellow}{}{color_reset}", file.display());
+            let mut lengths = Vec::new();
+            for (song, detected) in cache.tunes[&md5].iter().enumerate() {
+                match detected {
+                    Some(detected) => {
+                        eprintln!(
+                            "{color_cyan}{:>3}: {color_green}{}{color_reset}",
+                            song + 1,
+                            detected.describe()
+                        );
+                        lengths.push(detected.length());
+                    }
+                    None => {
+                        eprintln!(
+                            "{color_cyan}{:>3}: {color_red}no loop found{color_reset}",
+                            song + 1
+                        );
+                        lengths.push(songlength::DEFAULT_SONG_LENGTH);
+                    }
+                }
+            }
+            let relative = file.strip_prefix(dir).unwrap_or(&file);
+            println!("; /{}", relative.to_string_lossy().replace('\\', "/"));
+            let lengths: Vec<_> = lengths
+                .into_iter()
+                .map(songlength::format_precise_time)
+                .collect();
+            println!("{md5}={}", lengths.join(" "));
+        }
+    }
+    Ok(())
+}
 
-    let _ = test_tread.join();
+fn collect_sid_files(path: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    if path.is_dir() {
+        let mut entries = std::fs::read_dir(path)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<std::io::Result<Vec<_>>>()?;
+        entries.sort();
+        for entry in entries {
+            collect_sid_files(&entry, files)?;
+        }
+    } else if path
+        .extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("sid"))
+    {
+        files.push(path.to_owned());
+    }
+    Ok(())
+}
 
+pub fn list_devices() -> Result<()> {
+    for host in output::hosts() {
+        println!("{color_yellow}{host}{color_reset}");
+        let default = output::default_device(Some(host));
+        for device in output::devices(Some(host))? {
+            let marker = if Some(&device) == default.as_ref() {
+                "*"
+            } else {
+                " "
+            };
+            println!("{color_cyan}{marker} {color_blue}{device}{color_reset}");
+        }
+    }
     Ok(())
 }
 
@@ -110,13 +1030,19 @@ pub fn print_info(sid_file: &SidFile) {
     }
 }
 
-pub fn print_sound_info(sound: &Output) -> Result<()> {
-    eprintln!("Output device: {}", sound.device().name()?);
-    eprintln!(
-        "Supported stream config: {:?}",
-        sound.device().default_output_config()?
-    );
-    eprintln!("Stream config: {:?}", sound.stream_config());
+pub fn print_sound_info(sound: &Output) {
+    report!("Output device: {}", sound.device_name());
+    report!("Stream config: {:?}", sound.stream_config());
+    report!("Sample format: {}", sound.sample_format());
+}
 
-    Ok(())
+pub fn print_buffer_stats(device: &DeviceOutput) {
+    let stats = device.stats();
+    report!(
+        "Buffered: {:>4} ms of {} ms, underruns: {}, overruns: {}",
+        device.buffered().as_millis(),
+        device.latency().as_millis(),
+        stats.underruns(),
+        stats.overruns()
+    );
 }
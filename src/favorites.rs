@@ -0,0 +1,138 @@
+use crate::playlist::Entry;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+/// Star and rating of a tune or one of its subtunes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Mark {
+    pub starred: bool,
+    /// 1 to [`Mark::MAX_RATING`] stars.
+    pub rating: Option<u8>,
+}
+
+impl Mark {
+    pub const MAX_RATING: u8 = 5;
+
+    fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// `★★★☆☆`, or nothing when unrated.
+    pub fn stars(&self) -> String {
+        let Some(rating) = self.rating else {
+            return String::new();
+        };
+        (1..=Self::MAX_RATING)
+            .map(|star| if star <= rating { '★' } else { '☆' })
+            .collect()
+    }
+}
+
+/// Marks of one tune, by HVSC MD5 in [`Favorites`].
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MarkedTune {
+    /// Where the tune was last loaded from, kept current so moving files
+    /// around doesn't lose it.
+    pub path: String,
+    /// `Author - Name`, for lists.
+    pub title: String,
+    /// The tune as a whole.
+    pub tune: Mark,
+    pub songs: BTreeMap<u16, Mark>,
+}
+
+/// A starred tune or subtune to list.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Favorite {
+    pub entry: Entry,
+    pub title: String,
+    pub mark: Mark,
+}
+
+/// Starred and rated tunes by HVSC MD5 (`favorites.json`), so they survive
+/// reorganizing the collection.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Favorites {
+    pub tunes: HashMap<String, MarkedTune>,
+}
+
+impl Favorites {
+    /// The mark of the whole tune with `song` being `None`.
+    pub fn get(&self, md5: &str, song: Option<u16>) -> Mark {
+        let Some(tune) = self.tunes.get(md5) else {
+            return Mark::default();
+        };
+        match song {
+            Some(song) => tune.songs.get(&song).copied().unwrap_or_default(),
+            None => tune.tune,
+        }
+    }
+
+    /// Marks a tune loaded from `path`, or `song` of it. Tunes left without
+    /// any mark are forgotten.
+    pub fn set(&mut self, md5: &str, song: Option<u16>, path: &str, title: &str, mark: Mark) {
+        let tune = self.tunes.entry(md5.to_owned()).or_default();
+        tune.path = path.to_owned();
+        tune.title = title.to_owned();
+        match song {
+            Some(song) if mark.is_empty() => {
+                tune.songs.remove(&song);
+            }
+            Some(song) => {
+                tune.songs.insert(song, mark);
+            }
+            None => tune.tune = mark,
+        }
+        if tune.tune.is_empty() && tune.songs.is_empty() {
+            self.tunes.remove(md5);
+        }
+    }
+
+    /// Notes where a marked tune was loaded from. `true` when it moved.
+    pub fn relocate(&mut self, md5: &str, path: &str) -> bool {
+        match self.tunes.get_mut(md5) {
+            Some(tune) if tune.path != path => {
+                tune.path = path.to_owned();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Starred tunes and subtunes by title, each tune before its subtunes.
+    pub fn starred(&self) -> Vec<Favorite> {
+        let mut tunes: Vec<&MarkedTune> = self.tunes.values().collect();
+        tunes.sort_by(|a, b| a.title.cmp(&b.title).then(a.path.cmp(&b.path)));
+        let mut favorites = Vec::new();
+        for tune in tunes {
+            if tune.tune.starred {
+                favorites.push(Favorite {
+                    entry: Entry::new(tune.path.clone(), None),
+                    title: tune.title.clone(),
+                    mark: tune.tune,
+                });
+            }
+            for (&song, &mark) in tune.songs.iter().filter(|(_, mark)| mark.starred) {
+                favorites.push(Favorite {
+                    entry: Entry::new(tune.path.clone(), Some(song)),
+                    title: format!("{} #{song}", tune.title),
+                    mark,
+                });
+            }
+        }
+        favorites
+    }
+}
+
+impl Favorites {
+    pub fn load() -> Self {
+        crate::settings::load_json("favorites.json")
+    }
+
+    pub fn save(&self) -> anyhow::Result<()> {
+        crate::settings::save_json("favorites.json", self)
+    }
+}
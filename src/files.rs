@@ -1,9 +1,10 @@
-static CURRENT_DIR: &str = env!("CARGO_MANIFEST_DIR");
+pub static CURRENT_DIR: &str = env!("CARGO_MANIFEST_DIR");
 
 #[cfg(not(target_arch = "wasm32"))]
 mod fs {
     use serde_json::Value;
     use std::fs::File;
+    use vicious::songlength::SongLengths;
 
     pub fn files() -> Value {
         let json_path = format!("{}/C64Music.json", super::CURRENT_DIR);
@@ -12,19 +13,25 @@ mod fs {
         json
     }
 
+    /// Reads a file by collection path, or any path when absolute. Missing
+    /// files read as empty and fail to parse.
     pub fn open(filename: &str) -> Vec<u8> {
-        let full = format!("{}/{}", super::CURRENT_DIR, filename);
-        let path = std::path::Path::new(&full);
-        let data = std::fs::read(path).unwrap();
-        data
+        let path = std::path::Path::new(super::CURRENT_DIR).join(filename);
+        std::fs::read(path).unwrap_or_default()
+    }
+
+    pub fn song_lengths() -> SongLengths {
+        let full = format!("{}/{}", super::CURRENT_DIR, SongLengths::FILE_NAME);
+        SongLengths::load(std::path::Path::new(&full)).unwrap_or_default()
     }
 }
 #[cfg(not(target_arch = "wasm32"))]
-pub use fs::{files, open};
+pub use fs::{files, open, song_lengths};
 
 #[cfg(target_arch = "wasm32")]
 mod fetch {
     use serde_json::Value;
+    use vicious::songlength::SongLengths;
     use wasm_bindgen_futures::JsFuture;
     use web_sys::js_sys::Uint8Array;
     use web_sys::wasm_bindgen::JsCast;
@@ -59,6 +66,10 @@ mod fetch {
 
         Vec::new()
     }
+
+    pub fn song_lengths() -> SongLengths {
+        SongLengths::default()
+    }
 }
 #[cfg(target_arch = "wasm32")]
-pub use fetch::{files, open};
+pub use fetch::{files, open, song_lengths};
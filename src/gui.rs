@@ -1,15 +1,17 @@
 mod app;
 mod files;
-mod output;
-mod sid_player;
 
 use app::App;
 use eframe::egui::ViewportBuilder;
 
+// Catches allocations in the audio callback in debug builds, see
+// `vicious::output::Output`.
+#[cfg(debug_assertions)]
+#[global_allocator]
+static ALLOCATOR: assert_no_alloc::AllocDisabler = assert_no_alloc::AllocDisabler;
+
 #[cfg(not(target_arch = "wasm32"))]
 fn main() -> eframe::Result {
-    use std::time::{Duration, Instant};
-
     pretty_env_logger::init();
     let options = eframe::NativeOptions {
         viewport: ViewportBuilder::default().with_inner_size([640.0, 480.0]),
@@ -17,18 +19,6 @@ fn main() -> eframe::Result {
     };
 
     let app = Box::<App>::default();
-    let sid_player_thread = app.sid_player.clone();
-    let mut last_step = Instant::now();
-
-    std::thread::spawn(move || loop {
-        if last_step.elapsed() < Duration::from_millis(20) {
-            continue;
-        }
-
-        last_step = Instant::now();
-
-        sid_player_thread.lock().step();
-    });
 
     eframe::run_native("Vicious", options, Box::new(|_cc| Ok(app)))
 }
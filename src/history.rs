@@ -0,0 +1,312 @@
+use crate::{player_thread::Status, playlist::Entry, radio};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sid_file::SidFile;
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+    time::{Duration, Instant},
+};
+
+/// One playback of a subtune.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Play {
+    /// HVSC MD5 of the tune.
+    pub md5: String,
+    pub path: String,
+    pub song: u16,
+    pub name: String,
+    pub author: String,
+    pub released: String,
+    /// Seconds since the Unix epoch.
+    pub started: u64,
+    pub listened_ms: u64,
+}
+
+impl Play {
+    /// A play of `song` starting now.
+    pub fn new(md5: &str, path: &str, song: u16, sid_file: &SidFile) -> Self {
+        Self {
+            md5: md5.to_owned(),
+            path: path.to_owned(),
+            song,
+            name: sid_file.name.clone(),
+            author: sid_file.author.clone(),
+            released: sid_file.released.clone(),
+            started: radio::unix_time(),
+            listened_ms: 0,
+        }
+    }
+
+    pub fn listened(&self) -> Duration {
+        Duration::from_millis(self.listened_ms)
+    }
+
+    /// `Author - Name #song`, for lists.
+    pub fn title(&self) -> String {
+        format!("{} - {} #{}", self.author, self.name, self.song)
+    }
+}
+
+/// Plays and listening time of a composer or a release year.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tally {
+    pub name: String,
+    pub plays: usize,
+    pub listened: Duration,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Stats {
+    pub plays: usize,
+    pub listened: Duration,
+    /// Most played first.
+    pub composers: Vec<Tally>,
+    /// By the year the tunes were released, oldest first, unknown last.
+    pub years: Vec<Tally>,
+}
+
+/// Every playback, oldest first. Saved to `history.json` when a play starts
+/// and on exit.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct History {
+    pub plays: Vec<Play>,
+    /// Whether the last play is still going on.
+    #[serde(skip)]
+    following: bool,
+    /// When listening time was last added to it, `None` while paused.
+    #[serde(skip)]
+    counted: Option<Instant>,
+}
+
+impl History {
+    /// Plays shorter than this aren't exported, as they were skipped.
+    pub const MIN_LISTEN: Duration = Duration::from_secs(30);
+    /// Longer gaps between updates are the machine sleeping, not
+    /// listening.
+    const MAX_STEP: Duration = Duration::from_secs(60);
+
+    /// Whether the last play is of `song` of the tune and still followed.
+    fn is_current(&self, md5: &str, song: u16) -> bool {
+        self.following
+            && self
+                .plays
+                .last()
+                .is_some_and(|play| play.md5 == md5 && play.song == song)
+    }
+
+    fn start(&mut self, play: Play) {
+        self.plays.push(play);
+        self.following = true;
+        self.counted = Some(Instant::now());
+    }
+
+    /// Ends the last play, so playing the same song again counts anew.
+    pub fn finish(&mut self) {
+        self.following = false;
+        self.counted = None;
+    }
+
+    /// Follows the player playing a tune: starts a play when another song
+    /// started, adds the time spent playing otherwise. `true` when a play
+    /// was started, which is when the history is worth saving.
+    pub fn follow(&mut self, status: &Status, md5: &str, path: &str, sid_file: &SidFile) -> bool {
+        if self.is_current(md5, status.song) {
+            self.listen(status.playing);
+            false
+        } else if status.playing && status.song > 0 {
+            self.start(Play::new(md5, path, status.song, sid_file));
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Adds the wall-clock time since the last call to the last play, when
+    /// it kept playing. Unlike the song position this doesn't depend on
+    /// the speed, and seeking doesn't count.
+    fn listen(&mut self, playing: bool) {
+        let now = Instant::now();
+        let last = std::mem::replace(&mut self.counted, playing.then_some(now));
+        let (Some(last), true, Some(play)) = (last, playing, self.plays.last_mut()) else {
+            return;
+        };
+        let step = now - last;
+        if step <= Self::MAX_STEP {
+            play.listened_ms += step.as_millis() as u64;
+        }
+    }
+
+    /// Subtunes played last, most recent first, each once.
+    pub fn recent(&self, count: usize) -> Vec<(Entry, String)> {
+        let mut seen = HashSet::new();
+        self.plays
+            .iter()
+            .rev()
+            .filter(|play| seen.insert((&play.path, play.song)))
+            .take(count)
+            .map(|play| (Entry::new(play.path.clone(), Some(play.song)), play.title()))
+            .collect()
+    }
+
+    /// When each tune was last started, by HVSC MD5 in seconds since the
+    /// Unix epoch.
+    pub fn last_played(&self) -> HashMap<String, u64> {
+        let mut tunes = HashMap::new();
+        for play in &self.plays {
+            tunes.insert(play.md5.clone(), play.started);
+        }
+        tunes
+    }
+
+    pub fn stats(&self) -> Stats {
+        let mut composers: HashMap<&str, Tally> = HashMap::new();
+        let mut years: HashMap<Option<u16>, Tally> = HashMap::new();
+        let mut stats = Stats::default();
+        for play in &self.plays {
+            stats.plays += 1;
+            stats.listened += play.listened();
+            let year = radio::release_year(&play.released);
+            let tallies = [
+                composers
+                    .entry(&play.author)
+                    .or_insert_with(|| tally(&play.author)),
+                years.entry(year).or_insert_with(|| {
+                    tally(&year.map_or("Unknown".to_owned(), |year| year.to_string()))
+                }),
+            ];
+            for tally in tallies {
+                tally.plays += 1;
+                tally.listened += play.listened();
+            }
+        }
+        stats.composers = composers.into_values().collect();
+        stats.composers.sort_by(|a, b| {
+            b.plays
+                .cmp(&a.plays)
+                .then(b.listened.cmp(&a.listened))
+                .then(a.name.cmp(&b.name))
+        });
+        let mut years: Vec<_> = years.into_iter().collect();
+        years.sort_by_key(|(year, _)| year.unwrap_or(u16::MAX));
+        stats.years = years.into_iter().map(|(_, tally)| tally).collect();
+        stats
+    }
+
+    /// The plays long enough to count as listens, in the JSON format
+    /// ListenBrainz imports: an array of listens with their metadata.
+    pub fn listenbrainz(&self) -> Value {
+        let listens = self
+            .plays
+            .iter()
+            .filter(|play| play.listened() >= Self::MIN_LISTEN)
+            .map(|play| {
+                json!({
+                    "listened_at": play.started,
+                    "track_metadata": {
+                        "artist_name": play.author,
+                        "track_name": play.name,
+                        "additional_info": {
+                            "tracknumber": play.song,
+                            "listened_ms": play.listened_ms,
+                            "media_player": "vicious",
+                            "submission_client": "vicious",
+                            "hvsc_md5": play.md5,
+                        },
+                    },
+                })
+            })
+            .collect();
+        Value::Array(listens)
+    }
+
+    pub fn export_listenbrainz(&self, path: &Path) -> Result<()> {
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir)?;
+        }
+        let file = std::fs::File::create(path)
+            .with_context(|| format!("can't write {}", path.display()))?;
+        serde_json::to_writer_pretty(std::io::BufWriter::new(file), &self.listenbrainz())?;
+        Ok(())
+    }
+}
+
+fn tally(name: &str) -> Tally {
+    Tally {
+        name: name.to_owned(),
+        plays: 0,
+        listened: Duration::ZERO,
+    }
+}
+
+impl History {
+    pub fn load() -> Self {
+        crate::settings::load_json("history.json")
+    }
+
+    pub fn save(&self) -> anyhow::Result<()> {
+        crate::settings::save_json("history.json", self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread::sleep;
+
+    fn sid_file() -> SidFile {
+        let mut file = b"PSID\0\x01\0\x76\x10\0\x10\0\x10\x03\0\x02\0\x01\0\0\0\0".to_vec();
+        file.resize(0x76, 0);
+        file.extend([0x60, 0x60]);
+        SidFile::parse(&file).unwrap()
+    }
+
+    fn status(playing: bool, song: u16, position: Duration) -> Status {
+        Status {
+            playing,
+            song,
+            songs: 2,
+            position,
+            ..Status::default()
+        }
+    }
+
+    #[test]
+    fn counts_time_spent_playing() {
+        let sid_file = sid_file();
+        let mut history = History::default();
+        let mut follow = |status| history.follow(&status, "md5", "tune.sid", &sid_file);
+        assert!(!follow(status(false, 1, Duration::ZERO)));
+        assert!(follow(status(true, 1, Duration::ZERO)));
+        sleep(Duration::from_millis(50));
+        // Seeking or playing faster doesn't add to it.
+        assert!(!follow(status(true, 1, Duration::from_secs(100))));
+        assert!(!follow(status(false, 1, Duration::from_secs(100))));
+        sleep(Duration::from_millis(500));
+        assert!(!follow(status(false, 1, Duration::from_secs(100))));
+        assert!(!follow(status(true, 1, Duration::from_secs(100))));
+        // Another song is another play.
+        assert!(follow(status(true, 2, Duration::ZERO)));
+
+        assert_eq!(history.plays.len(), 2);
+        let listened = history.plays[0].listened_ms;
+        assert!((50..500).contains(&listened), "{listened} ms");
+        assert_eq!(history.plays[1].song, 2);
+    }
+
+    #[test]
+    fn finished_plays_start_anew() {
+        let sid_file = sid_file();
+        let mut history = History::default();
+        let playing = status(true, 1, Duration::ZERO);
+        assert!(history.follow(&playing, "md5", "tune.sid", &sid_file));
+        assert!(!history.follow(&playing, "md5", "tune.sid", &sid_file));
+        history.finish();
+        assert!(history.follow(&playing, "md5", "tune.sid", &sid_file));
+        assert_eq!(history.recent(10).len(), 1);
+        assert_eq!(history.stats().plays, 2);
+    }
+}
@@ -0,0 +1,326 @@
+use anyhow::{bail, ensure, Context, Result};
+use serde::{Deserialize, Serialize};
+use sid_file::SidFile;
+
+/// Offset of the big endian flags word in a v2+ PSID/RSID header.
+const FLAGS_OFFSET: usize = 0x76;
+/// Clock bits of the flags word meaning NTSC only.
+const CLOCK_NTSC: u16 = 0b10 << 2;
+/// SID model bits of the flags word.
+const MODEL_SHIFT: u16 = 4;
+/// RSID flag of tunes started from BASIC, which have no init address.
+const BASIC: u16 = 0b10;
+/// Song speed bytes as the HVSC fingerprint knows them.
+const SPEED_VBI: u8 = 0;
+const SPEED_CIA: u8 = 60;
+
+/// HVSC fingerprint of a SID file in the old algorithm, which HVSC
+/// releases before 68 used in `Songlengths.md5`: the C64 data, init and
+/// play address, song count, every song's speed and whether the tune is
+/// NTSC only. Headers that only differ in texts hash alike, so this is the
+/// key tunes are known by here.
+pub fn md5(file: &[u8]) -> Result<String> {
+    let header = Header::parse(file)?;
+    let data = file.get(header.data_offset as usize..).unwrap_or_default();
+    // A zero load address means the data starts with the real one.
+    let (data, load_address) = match header.load_address {
+        0 => (
+            data.get(2..).unwrap_or_default(),
+            data.get(..2)
+                .map_or(0, |bytes| u16::from_le_bytes([bytes[0], bytes[1]])),
+        ),
+        load_address => (data, load_address),
+    };
+    ensure!(!data.is_empty(), "SID file has no C64 data");
+    let init_address = match header.init_address {
+        0 if !header.is_basic() => load_address,
+        init_address => init_address,
+    };
+    let play_address = match header.play_address {
+        // Once reserved, read as 0 by the HVSC tools.
+        0xffff => 0,
+        play_address => play_address,
+    };
+
+    let mut context = md5::Context::new();
+    context.consume(data);
+    context.consume(init_address.to_le_bytes());
+    context.consume(play_address.to_le_bytes());
+    context.consume(header.songs.to_le_bytes());
+    for song in 1..=header.songs {
+        context.consume([header.song_speed(song)]);
+    }
+    if header.flags & (0b11 << 2) == CLOCK_NTSC {
+        context.consume([2u8]);
+    }
+    Ok(format!("{:x}", context.compute()))
+}
+
+/// HVSC fingerprint of a SID file in the full file algorithm of HVSC 68
+/// and later: the MD5 of the whole file, header included.
+pub fn full_md5(file: &[u8]) -> String {
+    format!("{:x}", md5::compute(file))
+}
+
+/// SID chip a tune was written for, from the header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SidModel {
+    Unknown,
+    Mos6581,
+    Mos8580,
+    /// Sounds right on either.
+    Both,
+}
+
+impl SidModel {
+    pub fn of(file: &[u8], sid_file: &SidFile) -> Self {
+        match (flags(file, sid_file) >> MODEL_SHIFT) & 0b11 {
+            0b01 => Self::Mos6581,
+            0b10 => Self::Mos8580,
+            0b11 => Self::Both,
+            _ => Self::Unknown,
+        }
+    }
+
+    /// Whether a tune for this model suits `wanted`. Tunes for both, or of
+    /// unknown model, suit either.
+    pub fn suits(self, wanted: SidModel) -> bool {
+        matches!(self, Self::Both | Self::Unknown) || self == wanted || wanted == Self::Both
+    }
+}
+
+/// The raw flags word of v2+ headers. `sid_file` decodes the flags one bit
+/// off, so they are read directly.
+fn flags(file: &[u8], sid_file: &SidFile) -> u16 {
+    file.get(FLAGS_OFFSET..FLAGS_OFFSET + 2)
+        .filter(|_| !matches!(sid_file.version, sid_file::Version::V1))
+        .map_or(0, |bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+/// The header fields the old fingerprint covers, read directly as
+/// `sid_file` rejects RSID tunes started from BASIC, whose init address
+/// is 0.
+struct Header {
+    rsid: bool,
+    data_offset: u16,
+    load_address: u16,
+    init_address: u16,
+    play_address: u16,
+    songs: u16,
+    speed: u32,
+    /// 0 for v1 headers, which have none.
+    flags: u16,
+}
+
+impl Header {
+    fn parse(file: &[u8]) -> Result<Self> {
+        let word = |offset: usize| {
+            file.get(offset..offset + 2)
+                .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+                .context("SID header is truncated")
+        };
+        let rsid = match file.get(..4) {
+            Some(b"PSID") => false,
+            Some(b"RSID") => true,
+            _ => bail!("not a PSID or RSID file"),
+        };
+        let version = word(0x04)?;
+        Ok(Self {
+            rsid,
+            data_offset: word(0x06)?,
+            load_address: word(0x08)?,
+            init_address: word(0x0a)?,
+            play_address: word(0x0c)?,
+            songs: word(0x0e)?,
+            speed: file
+                .get(0x12..0x16)
+                .map(|bytes| u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+                .context("SID header is truncated")?,
+            flags: if version >= 2 { word(FLAGS_OFFSET)? } else { 0 },
+        })
+    }
+
+    fn is_basic(&self) -> bool {
+        self.rsid && self.flags & BASIC != 0
+    }
+
+    /// Songs past the 32nd share the speed bit of the 32nd. RSID tunes
+    /// always count as CIA timed.
+    fn song_speed(&self, song: u16) -> u8 {
+        if self.rsid {
+            return SPEED_CIA;
+        }
+        let bit = (song - 1).min(31);
+        if self.speed & (1 << bit) != 0 {
+            SPEED_CIA
+        } else {
+            SPEED_VBI
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CODE: [u8; 10] = [0xa9, 0x00, 0x8d, 0x18, 0xd4, 0x60, 0xee, 0x20, 0xd0, 0x60];
+    const PAL: u16 = 0b01 << 2;
+    const MOS6581: u16 = 0b01 << MODEL_SHIFT;
+
+    /// A SID file with `data` after a header of `version`, `addresses`
+    /// being load, init and play.
+    fn sid(
+        magic: &[u8; 4],
+        version: u16,
+        addresses: [u16; 3],
+        songs: u16,
+        speed: u32,
+        flags: u16,
+        data: &[u8],
+    ) -> Vec<u8> {
+        let mut file = magic.to_vec();
+        let data_offset: u16 = if version == 1 { 0x76 } else { 0x7c };
+        for word in [
+            version,
+            data_offset,
+            addresses[0],
+            addresses[1],
+            addresses[2],
+            songs,
+            1,
+        ] {
+            file.extend(word.to_be_bytes());
+        }
+        file.extend(speed.to_be_bytes());
+        for text in ["Tune", "Composer", "1987 Label"] {
+            let mut field = [0; 32];
+            field[..text.len()].copy_from_slice(text.as_bytes());
+            file.extend(field);
+        }
+        if version >= 2 {
+            file.extend(flags.to_be_bytes());
+            file.extend([0; 4]);
+        }
+        file.extend(data);
+        file
+    }
+
+    fn psid(addresses: [u16; 3], songs: u16, speed: u32, flags: u16) -> Vec<u8> {
+        sid(b"PSID", 2, addresses, songs, speed, flags, &CODE)
+    }
+
+    #[test]
+    fn psid_v1() {
+        let file = sid(b"PSID", 1, [0x1000, 0x1000, 0x1006], 3, 0b010, 0, &CODE);
+        assert_eq!(md5(&file).unwrap(), "d958198a10f270b387312883ec251788");
+    }
+
+    #[test]
+    fn zero_load_address_and_reserved_play_address() {
+        let mut data = vec![0x00, 0x10];
+        data.extend(CODE);
+        let file = sid(b"PSID", 1, [0, 0, 0xffff], 1, 0, 0, &data);
+        assert_eq!(md5(&file).unwrap(), "0e8719ee577090539c64bece0e624af4");
+        // Stripped load address, init at the load address, play at 0.
+        let explicit = sid(b"PSID", 1, [0x1000, 0x1000, 0], 1, 0, 0, &CODE);
+        assert_eq!(md5(&explicit).unwrap(), md5(&file).unwrap());
+    }
+
+    #[test]
+    fn ntsc_only_tunes_hash_apart() {
+        let ntsc = psid([0x1000, 0x1000, 0x1006], 1, 0, CLOCK_NTSC | MOS6581);
+        let pal = psid([0x1000, 0x1000, 0x1006], 1, 0, PAL | MOS6581);
+        assert_eq!(md5(&ntsc).unwrap(), "28dd8dea0a2863d38ce524c358cd2434");
+        assert_eq!(md5(&pal).unwrap(), "db67529918c83f66674597f0f5ab0d78");
+        // Nothing but NTSC changes the fingerprint.
+        let unknown = psid([0x1000, 0x1000, 0x1006], 1, 0, 0);
+        assert_eq!(md5(&unknown).unwrap(), md5(&pal).unwrap());
+    }
+
+    #[test]
+    fn rsid_basic_keeps_init_address() {
+        let file = sid(b"RSID", 2, [0x0801, 0, 0], 1, 0, PAL | BASIC, &CODE);
+        assert_eq!(md5(&file).unwrap(), "dec6141077a779036e73e52aab56e301");
+    }
+
+    #[test]
+    fn songs_past_32_share_the_last_speed_bit() {
+        let file = psid([0x1000, 0x1000, 0x1006], 40, 0x8000_0001, 0);
+        assert_eq!(md5(&file).unwrap(), "c0ac535af600d64b3e318c93d3ead77e");
+    }
+
+    #[test]
+    fn texts_dont_matter() {
+        let file = psid([0x1000, 0x1000, 0x1006], 1, 0, PAL);
+        let mut renamed = file.clone();
+        renamed[0x16..0x1a].copy_from_slice(b"Song");
+        assert_eq!(md5(&renamed).unwrap(), md5(&file).unwrap());
+        assert_ne!(full_md5(&renamed), full_md5(&file));
+    }
+
+    #[test]
+    fn full_md5_hashes_the_whole_file() {
+        let file = psid([0x1000, 0x1000, 0x1006], 1, 0, CLOCK_NTSC | MOS6581);
+        assert_eq!(full_md5(&file), "6f0fcaad735e3fff9a6f48b5d8c892c6");
+    }
+
+    /// SID files under `dir` and its subdirectories.
+    fn sid_files(dir: &std::path::Path, files: &mut Vec<std::path::PathBuf>) {
+        for entry in std::fs::read_dir(dir).into_iter().flatten().flatten() {
+            let path = entry.path();
+            if path.is_dir() {
+                sid_files(&path, files);
+            } else if path
+                .extension()
+                .is_some_and(|extension| extension.eq_ignore_ascii_case("sid"))
+            {
+                files.push(path);
+            }
+        }
+    }
+
+    /// Checks both fingerprints against a real collection: every tune
+    /// must be listed in its `Songlengths.md5`, by the old MD5 before HVSC
+    /// 68 and by the full one since. Run with `cargo test -- --ignored`,
+    /// with `HVSC` set to the collection root or `./C64Music` present.
+    #[test]
+    #[ignore = "needs an HVSC collection"]
+    fn fingerprints_match_songlengths() {
+        use crate::songlength::SongLengths;
+
+        let root = std::env::var_os("HVSC").map_or_else(
+            || std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("C64Music"),
+            std::path::PathBuf::from,
+        );
+        let lengths = SongLengths::load(&root.join(SongLengths::HVSC_PATH)).unwrap();
+        let mut files = Vec::new();
+        sid_files(&root, &mut files);
+        assert!(!files.is_empty(), "no SID files in {}", root.display());
+        let (mut old, mut full, mut missing) = (0, 0, Vec::new());
+        for path in &files {
+            let data = std::fs::read(path).unwrap();
+            if lengths.get(&md5(&data).unwrap(), 1).is_some() {
+                old += 1;
+            } else if lengths.get(&full_md5(&data), 1).is_some() {
+                full += 1;
+            } else {
+                missing.push(path);
+            }
+        }
+        assert!(
+            missing.is_empty(),
+            "{} of {} tunes not listed, e.g. {:?}",
+            missing.len(),
+            files.len(),
+            &missing[..missing.len().min(5)]
+        );
+        // A release uses one algorithm throughout.
+        assert!(old == 0 || full == 0, "{old} old and {full} full MD5s");
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        assert!(md5(b"MThd\0\0\0\x06").is_err());
+        assert!(md5(&psid([0x1000, 0x1000, 0x1006], 1, 0, 0)[..0x7c]).is_err());
+    }
+}
@@ -0,0 +1,26 @@
+// The binaries install the allocator that catches allocations in the audio
+// callback. Tests install it here, so `assert_no_alloc` works in them too.
+#[cfg(test)]
+#[global_allocator]
+static ALLOCATOR: assert_no_alloc::AllocDisabler = assert_no_alloc::AllocDisabler;
+
+pub mod ab_loop;
+pub mod favorites;
+pub mod history;
+pub mod hvsc;
+pub mod loop_detection;
+pub mod loudness;
+pub mod output;
+pub mod player_thread;
+pub mod playlist;
+pub mod playlist_file;
+pub mod radio;
+pub mod random;
+pub mod settings;
+pub mod sid_player;
+pub mod silence;
+pub mod sink;
+pub mod snapshot;
+pub mod songlength;
+pub mod transition;
+pub mod volume;
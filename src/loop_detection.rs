@@ -0,0 +1,130 @@
+use crate::{sid_player::SidPlayer, sink::NullSink, songlength::format_time};
+use serde::{Deserialize, Serialize};
+use sid_file::SidFile;
+use std::{
+    collections::HashMap,
+    hash::{DefaultHasher, Hash, Hasher},
+    time::Duration,
+};
+
+/// How long the analyzer follows a subtune before giving up on a loop.
+pub const DEFAULT_MAX_LENGTH: Duration = Duration::from_secs(20 * 60);
+
+/// Where a subtune starts repeating, found by running its player.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct DetectedLength {
+    /// Time until the tune comes back to a state it was in before, or
+    /// until it stopped changing.
+    pub length_ms: u64,
+    /// Where playback continues once it repeats. `None` when the tune
+    /// ends rather than loops.
+    pub loop_start_ms: Option<u64>,
+}
+
+impl DetectedLength {
+    pub fn length(&self) -> Duration {
+        Duration::from_millis(self.length_ms)
+    }
+
+    pub fn loop_start(&self) -> Option<Duration> {
+        self.loop_start_ms.map(Duration::from_millis)
+    }
+
+    /// `3:12, loops from 0:08` or `1:05, ends`, for reports.
+    pub fn describe(&self) -> String {
+        match self.loop_start() {
+            Some(start) => format!(
+                "{}, loops from {}",
+                format_time(self.length()),
+                format_time(start)
+            ),
+            None => format!("{}, ends", format_time(self.length())),
+        }
+    }
+}
+
+/// Runs every subtune of `sid_file` headlessly for up to `max_length`,
+/// first song first. `None` for subtunes that didn't repeat in time.
+pub fn detect(sid_file: &SidFile, max_length: Duration) -> Vec<Option<DetectedLength>> {
+    let mut player = SidPlayer::new(Box::new(NullSink::new(false)));
+    player.load(sid_file);
+    (1..=sid_file.songs)
+        .map(|song| {
+            player.select_song(song);
+            player.play();
+            detect_song(&mut player, max_length)
+        })
+        .collect()
+}
+
+/// The play routine only sees memory, SID registers included, and the
+/// CPU registers it's called with, which are always the same. So once
+/// memory after a frame matches memory after an earlier one, every frame
+/// from there on repeats. The SID's own oscillators and envelopes aren't
+/// part of it; tunes reading them back may not be found to loop.
+fn detect_song(player: &mut SidPlayer, max_length: Duration) -> Option<DetectedLength> {
+    let mut seen = HashMap::new();
+    seen.insert(fingerprint(player.memory()), player.position());
+    let mut previous = player.position();
+    while player.position() < max_length {
+        if !player.skip_frame() {
+            return Some(ended(previous));
+        }
+        let position = player.position();
+        let state = fingerprint(player.memory());
+        if let Some(&start) = seen.get(&state) {
+            // Nothing changes from frame to frame any more: the tune is
+            // over, it didn't loop.
+            if start == previous {
+                return Some(ended(start));
+            }
+            return Some(DetectedLength {
+                length_ms: position.as_millis() as u64,
+                loop_start_ms: Some(start.as_millis() as u64),
+            });
+        }
+        seen.insert(state, position);
+        previous = position;
+    }
+    None
+}
+
+fn ended(at: Duration) -> DetectedLength {
+    DetectedLength {
+        length_ms: at.as_millis() as u64,
+        loop_start_ms: None,
+    }
+}
+
+/// 64 bits of hash instead of a copy of the whole memory for every frame.
+fn fingerprint(memory: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    memory.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Detected lengths by HVSC MD5, first song first (`lengths.json`). Used
+/// where `Songlengths.md5` has no entry.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LengthCache {
+    pub tunes: HashMap<String, Vec<Option<DetectedLength>>>,
+}
+
+impl LengthCache {
+    pub fn get(&self, md5: &str, song: u16) -> Option<Duration> {
+        let songs = self.tunes.get(md5)?;
+        let detected = (*songs.get(song.checked_sub(1)? as usize)?)?;
+        Some(detected.length())
+    }
+}
+
+impl LengthCache {
+    pub fn load() -> Self {
+        crate::settings::load_json("lengths.json")
+    }
+
+    pub fn save(&self) -> anyhow::Result<()> {
+        crate::settings::save_json("lengths.json", self)
+    }
+}
@@ -0,0 +1,327 @@
+use crate::{
+    sid_player::SidPlayer,
+    sink::{MemorySink, Sink},
+};
+use serde::{Deserialize, Serialize};
+use sid_file::SidFile;
+use std::{collections::HashMap, f64::consts::PI, time::Duration};
+
+/// Loudness all tunes are brought to when normalization is on, the
+/// ReplayGain 2.0 reference level.
+pub const TARGET_LUFS: f64 = -18.0;
+/// How much of each subtune the scanner renders.
+pub const DEFAULT_SCAN_DURATION: Duration = Duration::from_secs(120);
+
+/// Gating floor of EBU R128. Silent tunes measure as this.
+const ABSOLUTE_GATE_LUFS: f64 = -70.0;
+const RELATIVE_GATE_LU: f64 = -10.0;
+
+/// Which measurement playback normalizes to.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum Normalization {
+    #[default]
+    Off,
+    /// Every subtune on its own.
+    Track,
+    /// All subtunes of a file together, keeping their relative levels.
+    Album,
+}
+
+/// Measurement of one subtune.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Loudness {
+    /// Integrated loudness in LUFS.
+    pub lufs: f64,
+    /// Sample peak in dBFS.
+    pub peak_db: f64,
+}
+
+/// Measurements of every subtune of a file, first song first.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TuneLoudness {
+    pub songs: Vec<Loudness>,
+    /// The subtunes measured as one recording, gating all of their blocks
+    /// together. Caches from before it was measured no longer load, so
+    /// their tunes are measured again.
+    pub album: Loudness,
+}
+
+impl TuneLoudness {
+    pub fn song(&self, song: u16) -> Option<&Loudness> {
+        self.songs.get(song.checked_sub(1)? as usize)
+    }
+
+    /// Gain bringing `song` to [`TARGET_LUFS`], lowered where it would make
+    /// the peak clip.
+    pub fn gain_db(&self, song: u16, normalization: Normalization) -> Option<f64> {
+        let loudness = match normalization {
+            Normalization::Off => return None,
+            Normalization::Track => *self.song(song)?,
+            Normalization::Album => self.album,
+        };
+        if loudness.lufs <= ABSOLUTE_GATE_LUFS {
+            return None;
+        }
+        Some((TARGET_LUFS - loudness.lufs).min(-loudness.peak_db))
+    }
+}
+
+/// Renders every subtune of `sid_file` headlessly for `duration` and
+/// measures it.
+pub fn scan(sid_file: &SidFile, duration: Duration) -> TuneLoudness {
+    let sink = MemorySink::default();
+    let sample_rate = sink.sample_rate();
+    let mut player = SidPlayer::new(Box::new(sink.clone()));
+    player.load(sid_file);
+
+    let mut album = Meter::new(sample_rate);
+    let songs = (1..=sid_file.songs)
+        .map(|song| {
+            player.select_song(song);
+            player.play();
+            let mut meter = Meter::new(sample_rate);
+            let total = (duration.as_secs_f64() * sample_rate as f64) as usize
+                * SidPlayer::CHANNELS as usize;
+            let mut rendered = 0;
+            while rendered < total {
+                rendered += player.pump();
+                meter.add(&sink.take());
+            }
+            album.append(&meter);
+            meter.loudness()
+        })
+        .collect();
+    TuneLoudness {
+        songs,
+        album: album.loudness(),
+    }
+}
+
+/// EBU R128 integrated loudness and sample peak of a mono signal. It's
+/// measured as dual mono, the way it's heard on both speakers.
+pub struct Meter {
+    filters: [Biquad; 2],
+    step_size: usize,
+    /// Energy of the current 100 ms step so far.
+    step_energy: f64,
+    step_samples: usize,
+    /// Energies of the last four steps, making up a 400 ms block.
+    steps: [f64; 4],
+    steps_seen: usize,
+    blocks: Vec<f64>,
+    peak: i16,
+}
+
+impl Meter {
+    pub fn new(sample_rate: u32) -> Self {
+        Self {
+            filters: Biquad::k_weighting(sample_rate as f64),
+            step_size: (sample_rate / 10) as usize,
+            step_energy: 0.0,
+            step_samples: 0,
+            steps: [0.0; 4],
+            steps_seen: 0,
+            blocks: Vec::new(),
+            peak: 0,
+        }
+    }
+
+    pub fn add(&mut self, samples: &[i16]) {
+        for &sample in samples {
+            self.peak = self.peak.max(sample.saturating_abs());
+            let mut value = sample as f64 / -(i16::MIN as f64);
+            for filter in &mut self.filters {
+                value = filter.process(value);
+            }
+            self.step_energy += value * value;
+            self.step_samples += 1;
+
+            if self.step_samples == self.step_size {
+                self.steps[self.steps_seen % 4] = self.step_energy / self.step_size as f64;
+                self.steps_seen += 1;
+                if self.steps_seen >= 4 {
+                    self.blocks.push(self.steps.iter().sum::<f64>() / 4.0);
+                }
+                self.step_energy = 0.0;
+                self.step_samples = 0;
+            }
+        }
+    }
+
+    /// Adds the blocks and peak `other` measured, as if its signal had
+    /// followed this one.
+    pub fn append(&mut self, other: &Meter) {
+        self.blocks.extend_from_slice(&other.blocks);
+        self.peak = self.peak.max(other.peak);
+    }
+
+    pub fn loudness(&self) -> Loudness {
+        Loudness {
+            lufs: self.integrated(),
+            peak_db: 20.0 * (self.peak.max(1) as f64 / i16::MAX as f64).log10(),
+        }
+    }
+
+    /// Gated mean over the 400 ms blocks: first against the absolute
+    /// floor, then 10 LU below the loudness of what passed.
+    fn integrated(&self) -> f64 {
+        let absolute = gated_mean(&self.blocks, ABSOLUTE_GATE_LUFS);
+        let Some(absolute) = absolute else {
+            return ABSOLUTE_GATE_LUFS;
+        };
+        let relative = (block_loudness(absolute) + RELATIVE_GATE_LU).max(ABSOLUTE_GATE_LUFS);
+        gated_mean(&self.blocks, relative).map_or(ABSOLUTE_GATE_LUFS, block_loudness)
+    }
+}
+
+/// Loudness of a block with mean square `energy`, doubled for dual mono.
+fn block_loudness(energy: f64) -> f64 {
+    -0.691 + 10.0 * (2.0 * energy).log10()
+}
+
+fn gated_mean(blocks: &[f64], gate_lufs: f64) -> Option<f64> {
+    let (sum, count) = blocks
+        .iter()
+        .filter(|&&energy| block_loudness(energy) > gate_lufs)
+        .fold((0.0, 0), |(sum, count), energy| (sum + energy, count + 1));
+    (count > 0).then(|| sum / count as f64)
+}
+
+/// Direct form I biquad.
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    x: [f64; 2],
+    y: [f64; 2],
+}
+
+impl Biquad {
+    /// The two K-weighting stages of ITU-R BS.1770, a high shelf modelling
+    /// the head and a high pass, designed for any sample rate.
+    fn k_weighting(sample_rate: f64) -> [Self; 2] {
+        let (f0, gain_db, q) = (1681.974450955533, 3.999843853973347, 0.7071752369554196);
+        let k = (PI * f0 / sample_rate).tan();
+        let vh = 10f64.powf(gain_db / 20.0);
+        let vb = vh.powf(0.4996667741545416);
+        let a0 = 1.0 + k / q + k * k;
+        let shelf = Self::new(
+            [
+                (vh + vb * k / q + k * k) / a0,
+                2.0 * (k * k - vh) / a0,
+                (vh - vb * k / q + k * k) / a0,
+            ],
+            [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        );
+
+        let (f0, q) = (38.13547087602444, 0.5003270373238773);
+        let k = (PI * f0 / sample_rate).tan();
+        let a0 = 1.0 + k / q + k * k;
+        let high_pass = Self::new(
+            [1.0, -2.0, 1.0],
+            [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        );
+        [shelf, high_pass]
+    }
+
+    fn new(b: [f64; 3], a: [f64; 2]) -> Self {
+        Self {
+            b,
+            a,
+            x: [0.0; 2],
+            y: [0.0; 2],
+        }
+    }
+
+    fn process(&mut self, input: f64) -> f64 {
+        let output = self.b[0] * input + self.b[1] * self.x[0] + self.b[2] * self.x[1]
+            - self.a[0] * self.y[0]
+            - self.a[1] * self.y[1];
+        self.x = [input, self.x[0]];
+        self.y = [output, self.y[0]];
+        output
+    }
+}
+
+/// Cached measurements by HVSC MD5 (`loudness.json`).
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LoudnessCache {
+    pub tunes: HashMap<String, TuneLoudness>,
+}
+
+impl LoudnessCache {
+    pub fn load() -> Self {
+        crate::settings::load_json("loudness.json")
+    }
+
+    pub fn save(&self) -> anyhow::Result<()> {
+        crate::settings::save_json("loudness.json", self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `seconds` of a 1 kHz sine peaking at `dbfs`.
+    fn sine(dbfs: f64, seconds: f64, sample_rate: u32) -> Vec<i16> {
+        let amplitude = 10f64.powf(dbfs / 20.0) * i16::MAX as f64;
+        (0..(seconds * sample_rate as f64) as usize)
+            .map(|n| (amplitude * (2.0 * PI * 1000.0 * n as f64 / sample_rate as f64).sin()) as i16)
+            .collect()
+    }
+
+    fn meter(samples: &[i16]) -> Meter {
+        let mut meter = Meter::new(44100);
+        meter.add(samples);
+        meter
+    }
+
+    #[test]
+    fn sine_at_minus_23_dbfs_reads_minus_23_lufs() {
+        for sample_rate in [44100, 48000] {
+            let mut meter = Meter::new(sample_rate);
+            meter.add(&sine(-23.0, 5.0, sample_rate));
+            let loudness = meter.loudness();
+            assert!((loudness.lufs + 23.0).abs() < 0.1, "{loudness:?}");
+            assert!((loudness.peak_db + 23.0).abs() < 0.01, "{loudness:?}");
+        }
+    }
+
+    #[test]
+    fn silence_reads_as_the_absolute_gate() {
+        assert_eq!(meter(&[0; 44100]).loudness().lufs, ABSOLUTE_GATE_LUFS);
+    }
+
+    #[test]
+    fn quiet_parts_are_gated_out() {
+        let mut samples = sine(-23.0, 20.0, 44100);
+        samples.extend(vec![0; 44100 * 20]);
+        samples.extend(sine(-50.0, 20.0, 44100));
+        let lufs = meter(&samples).loudness().lufs;
+        // Only the blocks around the fade to silence count as well.
+        assert!((lufs + 23.0).abs() < 0.5, "{lufs}");
+    }
+
+    #[test]
+    fn album_gates_all_subtunes_together() {
+        let loud = meter(&sine(-23.0, 20.0, 44100));
+        let quiet = meter(&sine(-50.0, 20.0, 44100));
+        let mut album = Meter::new(44100);
+        album.append(&loud);
+        album.append(&quiet);
+        let tune = TuneLoudness {
+            songs: vec![loud.loudness(), quiet.loudness()],
+            album: album.loudness(),
+        };
+        // The quiet subtune is 27 LU down, below the relative gate.
+        let album = tune.album;
+        assert!((album.lufs - tune.songs[0].lufs).abs() < 1e-9, "{album:?}");
+        assert_eq!(album.peak_db, tune.songs[0].peak_db);
+        assert_eq!(
+            tune.gain_db(2, Normalization::Album),
+            Some(TARGET_LUFS - album.lufs)
+        );
+        assert!(tune.gain_db(2, Normalization::Track).unwrap() > 20.0);
+    }
+}
@@ -1,89 +1,795 @@
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
+use assert_no_alloc::assert_no_alloc;
 
 use cpal::{
     traits::{DeviceTrait, HostTrait, StreamTrait},
-    Device, Sample, Stream, StreamConfig,
+    BufferSize, Device, FromSample, Host, SampleFormat, SampleRate, SizedSample, Stream,
+    StreamConfig, StreamError, SupportedBufferSize, SupportedStreamConfig,
 };
 
-use log::info;
-use rb::{Consumer, RbConsumer};
+use crate::{
+    sid_player::SidPlayer,
+    sink::{DeviceSink, Waker},
+};
+use log::warn;
+use ringbuf::{
+    traits::{Consumer, Observer},
+    HeapCons, HeapProd, HeapRb,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+        mpsc::{self, Receiver, Sender},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+/// Host and device to open, by name. `None` means the system default.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OutputSelection {
+    pub host: Option<String>,
+    pub device: Option<String>,
+    /// Target latency between rendering and hearing a sample. Sizes both the
+    /// ring buffer and the device buffer.
+    pub latency_ms: u32,
+    /// Stops the stream while paused instead of playing silence, letting
+    /// the device sleep. Resuming then takes a little longer.
+    pub stop_when_paused: bool,
+}
+
+impl Default for OutputSelection {
+    fn default() -> Self {
+        Self {
+            host: None,
+            device: None,
+            latency_ms: Self::DEFAULT_LATENCY_MS,
+            stop_when_paused: false,
+        }
+    }
+}
+
+impl OutputSelection {
+    pub const DEFAULT_LATENCY_MS: u32 = 100;
+    pub const MIN_LATENCY_MS: u32 = 10;
+    pub const MAX_LATENCY_MS: u32 = 1000;
+
+    pub fn latency(&self) -> Duration {
+        Duration::from_millis(
+            self.latency_ms
+                .clamp(Self::MIN_LATENCY_MS, Self::MAX_LATENCY_MS) as u64,
+        )
+    }
+}
+
+/// Buffer problems counted since the [`DeviceOutput`] was opened. Shared
+/// with the audio callback and the sink, so it survives reconnects.
+#[derive(Debug, Default)]
+pub struct OutputStats {
+    underruns: AtomicU64,
+    overruns: AtomicU64,
+}
+
+impl OutputStats {
+    /// Callbacks that found fewer samples than the device asked for.
+    pub fn underruns(&self) -> u64 {
+        self.underruns.load(Ordering::Relaxed)
+    }
+
+    /// Writes that didn't fit into the ring buffer and lost samples.
+    pub fn overruns(&self) -> u64 {
+        self.overruns.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn underrun(&self) {
+        self.underruns.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn overrun(&self) {
+        self.overruns.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// State shared between a [`DeviceOutput`], its [`DeviceSink`] and the audio
+/// callback.
+#[derive(Debug, Default)]
+pub struct StreamState {
+    stats: OutputStats,
+    /// The callback ramps out and stops consuming, keeping buffered audio.
+    paused: AtomicBool,
+    /// Set by the callback once it has ramped out after a pause.
+    silent: AtomicBool,
+    /// Asks the callback to drop everything buffered.
+    flush: AtomicBool,
+}
+
+impl StreamState {
+    pub fn stats(&self) -> &OutputStats {
+        &self.stats
+    }
+
+    pub(crate) fn set_paused(&self, paused: bool) {
+        self.paused.store(paused, Ordering::Release);
+    }
+
+    pub(crate) fn request_flush(&self) {
+        self.flush.store(true, Ordering::Release);
+    }
+
+    /// Whether a flush is still waiting for the callback.
+    pub(crate) fn flushing(&self) -> bool {
+        self.flush.load(Ordering::Acquire)
+    }
+}
+
+/// Number of frames covering `latency` at `sample_rate`.
+pub fn latency_frames(latency: Duration, sample_rate: u32) -> usize {
+    (latency.as_micros() * sample_rate as u128 / 1_000_000) as usize
+}
+
+/// Everything the stream callbacks take ownership of.
+struct Feed {
+    consumer: HeapCons<i16>,
+    waker: Waker,
+    channels: u16,
+    errors: Sender<StreamError>,
+    state: Arc<StreamState>,
+}
 
 pub struct Output {
-    device: Device,
+    device_name: String,
     config: StreamConfig,
+    sample_format: SampleFormat,
     stream: Stream,
     sample_rate: u32,
+    errors: Receiver<StreamError>,
+    state: Arc<StreamState>,
 }
 
 impl Output {
-    pub fn new(consumer: Consumer<i16>) -> Result<Self> {
-        let host = cpal::default_host();
-        let device = host
-            .default_output_device()
-            .context("default output device available")?;
+    const PREFERRED_SAMPLE_RATE: SampleRate = SampleRate(44100);
 
-        let supported_config = device.default_output_config()?;
-        let config = supported_config.config();
+    /// Opens the selected output device. `channels` is the channel count of
+    /// the interleaved samples read from `consumer` (1 or 2). `waker` is
+    /// unparked whenever the device has consumed samples. Pausing, flushing
+    /// and underrun counting go through `state`.
+    pub fn new(
+        consumer: HeapCons<i16>,
+        waker: Waker,
+        channels: u16,
+        selection: &OutputSelection,
+        state: Arc<StreamState>,
+    ) -> Result<Self> {
+        let host = host(selection.host.as_deref())?;
+        let device = match &selection.device {
+            Some(name) => host
+                .output_devices()?
+                .find(|device| device.name().is_ok_and(|n| &n == name))
+                .with_context(|| format!("output device {name} not found"))?,
+            None => host
+                .default_output_device()
+                .context("default output device available")?,
+        };
 
+        let supported_config = Self::negotiate_config(&device)?;
+        let sample_format = supported_config.sample_format();
+        let mut config = supported_config.config();
         let sample_rate: u32 = config.sample_rate.0;
+        config.buffer_size = Self::buffer_size(&supported_config, selection.latency());
+        let (error_sender, errors) = mpsc::channel();
+        let feed = Feed {
+            consumer,
+            waker,
+            channels,
+            errors: error_sender,
+            state: state.clone(),
+        };
+
+        let stream = match sample_format {
+            SampleFormat::I8 => Self::build_stream::<i8>(&device, &config, feed),
+            SampleFormat::I16 => Self::build_stream::<i16>(&device, &config, feed),
+            SampleFormat::I32 => Self::build_stream::<i32>(&device, &config, feed),
+            SampleFormat::I64 => Self::build_stream::<i64>(&device, &config, feed),
+            SampleFormat::U8 => Self::build_stream::<u8>(&device, &config, feed),
+            SampleFormat::U16 => Self::build_stream::<u16>(&device, &config, feed),
+            SampleFormat::U32 => Self::build_stream::<u32>(&device, &config, feed),
+            SampleFormat::U64 => Self::build_stream::<u64>(&device, &config, feed),
+            SampleFormat::F32 => Self::build_stream::<f32>(&device, &config, feed),
+            SampleFormat::F64 => Self::build_stream::<f64>(&device, &config, feed),
+            format => bail!("unsupported sample format: {format}"),
+        }?;
+        stream.play()?;
+
+        Ok(Self {
+            device_name: device.name()?,
+            config,
+            sample_format,
+            stream,
+            sample_rate,
+            errors,
+            state,
+        })
+    }
+
+    /// Opens the selected device, falling back to the host's default device
+    /// when the selected one is gone. Used to recover from stream errors.
+    fn reopen(
+        buffer: &Arc<HeapRb<i16>>,
+        waker: &Waker,
+        channels: u16,
+        selection: &OutputSelection,
+        state: &Arc<StreamState>,
+    ) -> Result<Self> {
+        let consumer = || HeapCons::new(buffer.clone());
+        match Self::new(
+            consumer(),
+            waker.clone(),
+            channels,
+            selection,
+            state.clone(),
+        ) {
+            Err(err) if selection.device.is_some() => {
+                warn!("can't reopen selected output device: {err}");
+                let fallback = OutputSelection {
+                    device: None,
+                    ..selection.clone()
+                };
+                Self::new(
+                    consumer(),
+                    waker.clone(),
+                    channels,
+                    &fallback,
+                    state.clone(),
+                )
+            }
+            result => result,
+        }
+    }
+
+    /// Picks the best supported config using cpal's default heuristics
+    /// (stereo, mono, then more channels; f32, i16, u16), keeping the
+    /// device's default sample rate when the config allows it.
+    fn negotiate_config(device: &Device) -> Result<SupportedStreamConfig> {
+        let default_rate = device
+            .default_output_config()
+            .map(|config| config.sample_rate())
+            .unwrap_or(Self::PREFERRED_SAMPLE_RATE);
+
+        let range = device
+            .supported_output_configs()?
+            .filter(|range| Self::is_supported_format(range.sample_format()))
+            .max_by(|a, b| a.cmp_default_heuristics(b))
+            .context("output device supports no usable sample format")?;
+
+        let config = range
+            .try_with_sample_rate(default_rate)
+            .or_else(|| range.try_with_sample_rate(Self::PREFERRED_SAMPLE_RATE))
+            .unwrap_or_else(|| range.with_max_sample_rate());
+
+        Ok(config)
+    }
+
+    /// Device buffer for a quarter of the target latency, the ring buffer
+    /// holding the rest, clamped to what the device supports.
+    fn buffer_size(config: &SupportedStreamConfig, latency: Duration) -> BufferSize {
+        let frames = (latency_frames(latency, config.sample_rate().0) / 4) as u32;
+        match *config.buffer_size() {
+            SupportedBufferSize::Range { min, max } => BufferSize::Fixed(frames.clamp(min, max)),
+            SupportedBufferSize::Unknown => BufferSize::Default,
+        }
+    }
+
+    fn is_supported_format(format: SampleFormat) -> bool {
+        format.is_float() || ((format.is_int() || format.is_uint()) && format != SampleFormat::I24)
+    }
+
+    fn build_stream<T>(device: &Device, config: &StreamConfig, feed: Feed) -> Result<Stream>
+    where
+        T: SizedSample + FromSample<i16>,
+    {
+        let Feed {
+            consumer,
+            waker,
+            channels,
+            errors,
+            state,
+        } = feed;
+        let mut callback = Callback::new(
+            consumer,
+            waker,
+            state,
+            channels,
+            config.channels,
+            config.sample_rate.0,
+        );
         let stream = device.build_output_stream(
-            &config,
-            move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
-                info!("sound buffer len: {}", data.len());
-                let mut tmp: Vec<i16> = vec![0; data.len() / 2];
-                let readed = consumer.read(&mut tmp[..]).unwrap_or(0);
-                info!("{} samples received", readed == data.len() / 2);
-                if readed < data.len() / 2 {
-                    data.fill(0.0);
-                    return;
-                }
-                let new = tmp
-                    .iter()
-                    .flat_map(|&s| {
-                        let v = s.to_sample();
-                        vec![v, v]
-                    })
-                    .collect::<Vec<_>>();
-
-                data.copy_from_slice(&new[..]);
-
-                // let f: f32 = 15417.to_sample();
-                // info!("{} -> {}", 15417, f);
-                // for samples in data.chunks_mut(2) {
-                //     let val = tmp.pop().unwrap();
-                //     let sample_val: f32 = val.to_sample();
-                //     for sample in samples {
-                //         *sample = sample_val;
-                //     }
-                // }
+            config,
+            move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
+                assert_no_alloc(|| callback.fill(data))
             },
             move |err| {
-                dbg!("audio output error: {}", err);
+                let _ = errors.send(err);
             },
             None,
         )?;
 
-        Ok(Self {
-            device,
-            config,
-            stream,
-            sample_rate,
-        })
+        Ok(stream)
+    }
+
+    /// Returns the next error reported by the running stream, if any.
+    pub fn poll_error(&self) -> Option<StreamError> {
+        self.errors.try_recv().ok()
     }
 
     pub fn sample_rate(&self) -> u32 {
         self.sample_rate
     }
 
-    pub fn device(&self) -> &Device {
-        &self.device
+    pub fn sample_format(&self) -> SampleFormat {
+        self.sample_format
+    }
+
+    pub fn device_name(&self) -> &str {
+        &self.device_name
     }
 
     pub fn stream_config(&self) -> &StreamConfig {
         &self.config
     }
 
-    pub fn stream(&mut self) -> &mut Stream {
-        &mut self.stream
+    pub fn stats(&self) -> &OutputStats {
+        &self.state.stats
+    }
+
+    /// Starts or stops the cpal stream itself, e.g. to let the device sleep
+    /// while paused.
+    fn set_running(&self, running: bool) -> Result<()> {
+        if running {
+            self.stream.play()?;
+        } else {
+            self.stream.pause()?;
+        }
+        Ok(())
+    }
+}
+
+/// What the audio callback does, apart from cpal so it runs without a
+/// device.
+struct Callback {
+    consumer: HeapCons<i16>,
+    waker: Waker,
+    state: Arc<StreamState>,
+    source_channels: usize,
+    device_channels: usize,
+    /// Allocated once up front: the callback runs on the real-time audio
+    /// thread and must neither allocate, lock nor log.
+    samples: Vec<i16>,
+    ramp_step: f32,
+    gain: f32,
+}
+
+impl Callback {
+    /// Frames converted per pass, bounding the scratch buffer.
+    const CHUNK_FRAMES: usize = 1024;
+    /// Fade applied when pausing, resuming and after a flush.
+    const RAMP: Duration = Duration::from_millis(5);
+
+    fn new(
+        consumer: HeapCons<i16>,
+        waker: Waker,
+        state: Arc<StreamState>,
+        source_channels: u16,
+        device_channels: u16,
+        sample_rate: u32,
+    ) -> Self {
+        Self {
+            consumer,
+            waker,
+            state,
+            source_channels: source_channels as usize,
+            device_channels: device_channels as usize,
+            samples: vec![0; Self::CHUNK_FRAMES * source_channels as usize],
+            ramp_step: 1.0 / latency_frames(Self::RAMP, sample_rate).max(1) as f32,
+            gain: 0.0,
+        }
+    }
+
+    /// Fills `data` from the ring buffer, ramping in and out around pauses
+    /// and flushes, and wakes the rendering thread.
+    fn fill<T>(&mut self, data: &mut [T])
+    where
+        T: SizedSample + FromSample<i16>,
+    {
+        let state = &self.state;
+        if state.flush.load(Ordering::Acquire) {
+            self.consumer.skip(self.consumer.occupied_len());
+            self.gain = 0.0;
+            state.flush.store(false, Ordering::Release);
+        }
+        let paused = state.paused.load(Ordering::Acquire);
+        if paused && self.gain == 0.0 {
+            data.fill(T::EQUILIBRIUM);
+            state.silent.store(true, Ordering::Release);
+            return;
+        }
+        state.silent.store(false, Ordering::Release);
+
+        let target = if paused { 0.0 } else { 1.0 };
+        for chunk in data.chunks_mut(Self::CHUNK_FRAMES * self.device_channels) {
+            let frames = chunk.len() / self.device_channels;
+            let samples = &mut self.samples[..frames * self.source_channels];
+            let read = self.consumer.pop_slice(samples);
+            if read < samples.len() {
+                state.stats.underrun();
+                samples[read..].fill(0);
+            }
+
+            for (input, output) in samples
+                .chunks_exact_mut(self.source_channels)
+                .zip(chunk.chunks_exact_mut(self.device_channels))
+            {
+                if self.gain != target {
+                    self.gain =
+                        (self.gain + self.ramp_step.copysign(target - self.gain)).clamp(0.0, 1.0);
+                    for sample in input.iter_mut() {
+                        *sample = (*sample as f32 * self.gain) as i16;
+                    }
+                }
+                upmix(input, output);
+            }
+        }
+        if let Some(thread) = self.waker.get() {
+            thread.unpark();
+        }
+    }
+}
+
+/// What happened to a [`DeviceOutput`] since it was last polled.
+pub enum OutputEvent {
+    /// Reported by the stream. Only a device that went away closes it and
+    /// has it reopened; after others, like xruns, the stream keeps going.
+    Error(StreamError),
+    Reconnected,
+    ReconnectFailed(anyhow::Error, Duration),
+}
+
+/// The ring buffer feeding a [`DeviceSink`] together with the cpal stream
+/// draining it. The stream can be switched to another device or reopened
+/// after an error while the sink and the player keep going.
+pub struct DeviceOutput {
+    buffer: Arc<HeapRb<i16>>,
+    waker: Waker,
+    output: Option<Output>,
+    selection: OutputSelection,
+    sample_rate: Arc<AtomicU32>,
+    reconnect: Option<Backoff>,
+    state: Arc<StreamState>,
+    /// Whether the cpal stream is running, see [`OutputSelection::stop_when_paused`].
+    running: bool,
+}
+
+impl DeviceOutput {
+    /// Highest sample rate the ring buffer is sized for, so that switching
+    /// to another device never shortens the configured latency.
+    const MAX_SAMPLE_RATE: u32 = 192_000;
+
+    /// Opens the selected device with a ring buffer sized for the selected
+    /// latency. The sink keeps the buffer filled up to that latency only.
+    pub fn open(selection: &OutputSelection) -> Result<(Self, DeviceSink)> {
+        let latency = selection.latency();
+        let capacity =
+            latency_frames(latency, Self::MAX_SAMPLE_RATE) * SidPlayer::CHANNELS as usize;
+        let buffer = Arc::new(HeapRb::new(capacity));
+        let waker = Waker::default();
+        let state = Arc::new(StreamState::default());
+        let output = Output::reopen(&buffer, &waker, SidPlayer::CHANNELS, selection, &state)?;
+        let sample_rate = Arc::new(AtomicU32::new(output.sample_rate()));
+        let producer = HeapProd::new(buffer.clone());
+        let sink = DeviceSink::new(
+            producer,
+            sample_rate.clone(),
+            latency,
+            waker.clone(),
+            state.clone(),
+        );
+
+        let device = Self {
+            buffer,
+            waker,
+            output: Some(output),
+            selection: selection.clone(),
+            sample_rate,
+            reconnect: None,
+            state,
+            running: true,
+        };
+        Ok((device, sink))
+    }
+
+    /// Moves playback to another host or device. The sink picks up the new
+    /// sample rate on its next demand. When the new device can't be opened
+    /// the previous one is reopened.
+    pub fn switch(&mut self, selection: &OutputSelection) -> Result<()> {
+        self.output = None;
+        match Output::reopen(
+            &self.buffer,
+            &self.waker,
+            SidPlayer::CHANNELS,
+            selection,
+            &self.state,
+        ) {
+            Ok(output) => {
+                self.replace(output);
+                self.selection = selection.clone();
+                Ok(())
+            }
+            Err(err) => {
+                self.reconnect = Some(Backoff::default());
+                Err(err)
+            }
+        }
+    }
+
+    /// Reports stream errors and keeps trying to reopen the output with
+    /// backoff once the device is gone. Also stops and restarts the stream around pauses
+    /// when configured to. Meant to be called regularly from the UI thread.
+    pub fn poll(&mut self) -> Option<OutputEvent> {
+        self.update_running();
+        if let Some(err) = self.output.as_ref().and_then(Output::poll_error) {
+            if matches!(err, StreamError::DeviceNotAvailable) {
+                self.output = None;
+                self.reconnect.get_or_insert_with(Backoff::default);
+            }
+            return Some(OutputEvent::Error(err));
+        }
+
+        let backoff = self.reconnect.as_mut()?;
+        if !backoff.ready() {
+            return None;
+        }
+
+        match Output::reopen(
+            &self.buffer,
+            &self.waker,
+            SidPlayer::CHANNELS,
+            &self.selection,
+            &self.state,
+        ) {
+            Ok(output) => {
+                self.replace(output);
+                self.reconnect = None;
+                Some(OutputEvent::Reconnected)
+            }
+            Err(err) => {
+                backoff.failed();
+                Some(OutputEvent::ReconnectFailed(err, backoff.delay()))
+            }
+        }
+    }
+
+    /// The open output, `None` while reconnecting.
+    pub fn output(&self) -> Option<&Output> {
+        self.output.as_ref()
+    }
+
+    /// Underrun and overrun counters, kept across reconnects.
+    pub fn stats(&self) -> &OutputStats {
+        self.state.stats()
+    }
+
+    /// Stops the stream once the callback has ramped out after a pause and
+    /// starts it again on resume.
+    fn update_running(&mut self) {
+        let Some(output) = &self.output else {
+            return;
+        };
+        let running = !self.selection.stop_when_paused
+            || !self.state.paused.load(Ordering::Acquire)
+            || !self.state.silent.load(Ordering::Acquire);
+        if running != self.running {
+            match output.set_running(running) {
+                Ok(()) => self.running = running,
+                Err(err) => warn!("can't start or stop the output stream: {err}"),
+            }
+        }
+    }
+
+    /// How much audio is waiting in the ring buffer.
+    pub fn buffered(&self) -> Duration {
+        let rate = self.sample_rate.load(Ordering::Relaxed).max(1) as u64;
+        let frames = (self.buffer.occupied_len() / SidPlayer::CHANNELS as usize) as u64;
+        Duration::from_micros(frames * 1_000_000 / rate)
+    }
+
+    /// The latency the output was opened with.
+    pub fn latency(&self) -> Duration {
+        self.selection.latency()
+    }
+
+    fn replace(&mut self, output: Output) {
+        self.sample_rate
+            .store(output.sample_rate(), Ordering::Relaxed);
+        self.output = Some(output);
+        self.running = true;
+    }
+}
+
+/// Exponential backoff between attempts to reopen a lost output device.
+struct Backoff {
+    delay: Duration,
+    next_attempt: Instant,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            delay: Self::MIN_DELAY,
+            next_attempt: Instant::now(),
+        }
+    }
+}
+
+impl Backoff {
+    const MIN_DELAY: Duration = Duration::from_millis(250);
+    const MAX_DELAY: Duration = Duration::from_secs(8);
+
+    fn ready(&self) -> bool {
+        Instant::now() >= self.next_attempt
+    }
+
+    fn failed(&mut self) {
+        self.next_attempt = Instant::now() + self.delay;
+        self.delay = (self.delay * 2).min(Self::MAX_DELAY);
+    }
+
+    fn delay(&self) -> Duration {
+        self.delay
+    }
+}
+
+/// Names of the audio hosts cpal can use on this platform.
+pub fn hosts() -> Vec<&'static str> {
+    cpal::available_hosts()
+        .into_iter()
+        .map(|id| id.name())
+        .collect()
+}
+
+/// Names of the output devices of the given host (the default host when `None`).
+pub fn devices(host_name: Option<&str>) -> Result<Vec<String>> {
+    let host = host(host_name)?;
+    let devices = host
+        .output_devices()?
+        .filter_map(|device| device.name().ok())
+        .collect();
+    Ok(devices)
+}
+
+/// Name of the default output device of the given host.
+pub fn default_device(host_name: Option<&str>) -> Option<String> {
+    host(host_name)
+        .ok()?
+        .default_output_device()
+        .and_then(|device| device.name().ok())
+}
+
+fn host(name: Option<&str>) -> Result<Host> {
+    let Some(name) = name else {
+        return Ok(cpal::default_host());
+    };
+    let id = cpal::available_hosts()
+        .into_iter()
+        .find(|id| id.name().eq_ignore_ascii_case(name))
+        .with_context(|| format!("audio host {name} not available"))?;
+    Ok(cpal::host_from_id(id)?)
+}
+
+/// Spreads one mono or stereo input frame over a device frame. Mono goes to
+/// every channel; stereo keeps left and right on the first two channels and
+/// puts the downmix on the rest (or on the only channel of a mono device).
+fn upmix<T>(input: &[i16], output: &mut [T])
+where
+    T: SizedSample + FromSample<i16>,
+{
+    match (input, output.len()) {
+        (&[sample], _) => output.fill(T::from_sample(sample)),
+        (&[left, right], 1) => output[0] = T::from_sample(downmix(left, right)),
+        (&[left, right], _) => {
+            output[0] = T::from_sample(left);
+            output[1] = T::from_sample(right);
+            output[2..].fill(T::from_sample(downmix(left, right)));
+        }
+        _ => output.fill(T::EQUILIBRIUM),
+    }
+}
+
+fn downmix(left: i16, right: i16) -> i16 {
+    ((left as i32 + right as i32) / 2) as i16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ringbuf::traits::{Producer, Split};
+
+    /// A callback at 1 kHz, so ramps take 5 frames, and the producer
+    /// feeding it.
+    fn new_callback(source_channels: u16, device_channels: u16) -> (Callback, HeapProd<i16>) {
+        let (producer, consumer) = HeapRb::new(64).split();
+        let callback = Callback::new(
+            consumer,
+            Waker::default(),
+            Arc::default(),
+            source_channels,
+            device_channels,
+            1000,
+        );
+        (callback, producer)
+    }
+
+    #[test]
+    fn fills_from_the_ring_buffer_and_ramps_in() {
+        let (mut callback, mut producer) = new_callback(1, 1);
+        producer.push_slice(&[1000; 8]);
+        let mut data = [0i16; 10];
+        assert_no_alloc(|| callback.fill(&mut data));
+        assert_eq!(data, [200, 400, 600, 800, 1000, 1000, 1000, 1000, 0, 0]);
+        assert_eq!(callback.state.stats.underruns(), 1);
+    }
+
+    #[test]
+    fn pausing_ramps_out_then_keeps_the_buffer() {
+        let (mut callback, mut producer) = new_callback(1, 1);
+        callback.gain = 1.0;
+        producer.push_slice(&[1000; 16]);
+        callback.state.set_paused(true);
+        let mut data = [0i16; 6];
+        assert_no_alloc(|| callback.fill(&mut data));
+        assert_eq!(data, [800, 600, 400, 200, 0, 0]);
+        assert!(!callback.state.silent.load(Ordering::Acquire));
+
+        let mut data = [1i16; 6];
+        assert_no_alloc(|| callback.fill(&mut data));
+        assert_eq!(data, [0; 6]);
+        assert!(callback.state.silent.load(Ordering::Acquire));
+        assert_eq!(callback.consumer.occupied_len(), 10);
+
+        callback.state.set_paused(false);
+        assert_no_alloc(|| callback.fill(&mut data));
+        assert_eq!(data, [200, 400, 600, 800, 1000, 1000]);
+        assert_eq!(callback.state.stats.underruns(), 0);
+    }
+
+    #[test]
+    fn flushing_drops_the_buffer() {
+        let (mut callback, mut producer) = new_callback(1, 1);
+        callback.gain = 1.0;
+        producer.push_slice(&[1000; 16]);
+        callback.state.request_flush();
+        let mut data = [1i16; 4];
+        assert_no_alloc(|| callback.fill(&mut data));
+        assert_eq!(data, [0; 4]);
+        assert!(!callback.state.flushing());
+        assert_eq!(callback.consumer.occupied_len(), 0);
+        assert_eq!(callback.gain, 0.8);
+    }
+
+    #[test]
+    fn upmixes_to_the_device_channels() {
+        let (mut callback, mut producer) = new_callback(2, 4);
+        callback.gain = 1.0;
+        producer.push_slice(&[16384, 0, -16384, 16384]);
+        let mut data = [1.0f32; 8];
+        assert_no_alloc(|| callback.fill(&mut data));
+        assert_eq!(data, [0.5, 0.0, 0.25, 0.25, -0.5, 0.5, 0.0, 0.0]);
+
+        let (mut callback, mut producer) = new_callback(1, 2);
+        callback.gain = 1.0;
+        producer.push_slice(&[-16384, 16384]);
+        let mut data = [0i16; 4];
+        assert_no_alloc(|| callback.fill(&mut data));
+        assert_eq!(data, [-16384, -16384, 16384, 16384]);
     }
 }
@@ -0,0 +1,199 @@
+use crate::{
+    ab_loop::LoopRegion, sid_player::SidPlayer, silence::SilenceDetection, sink::Sink,
+    snapshot::Snapshot, transition::Transition, volume::Volume,
+};
+use sid_file::SidFile;
+use std::{
+    cell::Cell,
+    sync::mpsc::{self, Receiver, Sender, SyncSender, TryRecvError},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+/// What the UI can ask the player thread to do.
+pub enum Command {
+    Load(Box<SidFile>),
+    /// Moves on to a song of another tune with the configured transition.
+    Transition(Box<SidFile>, Option<u16>),
+    SetTransition(Transition),
+    Play,
+    Pause,
+    Stop,
+    Next,
+    Prev,
+    SelectSong(u16),
+    Seek(Duration),
+    /// Sends back a snapshot of the current state.
+    Snapshot(Sender<Box<Snapshot>>),
+    Restore(Box<Snapshot>),
+    /// Renders into another sink from now on, e.g. after a settings change.
+    SetSink(Box<dyn Sink>),
+    SetVolume(Volume),
+    /// Loudness normalization gain in dB for the current song.
+    SetNormalization(f32),
+    /// Tempo in percent.
+    SetSpeed(u32),
+    /// Loops a region of the current song, or stops looping.
+    SetLoop(Option<LoopRegion>),
+    SetSilenceDetection(SilenceDetection),
+    /// Flushes the sink and ends the thread.
+    Quit,
+}
+
+/// Read-only snapshot of the player published by its thread.
+#[derive(Debug, Clone, Default)]
+pub struct Status {
+    pub playing: bool,
+    pub song: u16,
+    pub songs: u16,
+    /// Emulated time since the current song was started.
+    pub position: Duration,
+    /// Peak level since the previous snapshot, 0.0 to 1.0.
+    pub level: f32,
+    pub ab_loop: Option<LoopRegion>,
+    /// How long the output has been below the silence threshold.
+    pub silence: Duration,
+    /// Commands handled before the status was taken.
+    pub commands: u64,
+}
+
+/// Handle to the thread owning the [`SidPlayer`]. Commands go in over a
+/// channel and status snapshots come back over another one, so neither side
+/// ever waits for the other.
+pub struct PlayerThread {
+    commands: Sender<Command>,
+    snapshots: Receiver<Status>,
+    status: Status,
+    /// Commands sent so far.
+    sent: Cell<u64>,
+    thread: JoinHandle<anyhow::Result<()>>,
+}
+
+impl PlayerThread {
+    /// How often the thread publishes a status snapshot.
+    const STATUS_INTERVAL: Duration = Duration::from_millis(20);
+
+    /// Starts pumping `player` on its own thread. With a `limit` the thread
+    /// ends by itself after rendering that much audio.
+    pub fn spawn(player: SidPlayer, limit: Option<Duration>) -> Self {
+        let (commands, receiver) = mpsc::channel();
+        let (publisher, snapshots) = mpsc::sync_channel(1);
+        let thread = thread::spawn(move || run(player, receiver, publisher, limit));
+        Self {
+            commands,
+            snapshots,
+            status: Status::default(),
+            sent: Cell::new(0),
+            thread,
+        }
+    }
+
+    /// Queues a command and wakes the thread to handle it right away.
+    pub fn send(&self, command: Command) {
+        // Only fails once the thread is gone, when there is nothing to do.
+        let _ = self.commands.send(command);
+        self.sent.set(self.sent.get() + 1);
+        self.thread.thread().unpark();
+    }
+
+    /// The latest status snapshot.
+    pub fn status(&mut self) -> &Status {
+        if let Ok(status) = self.snapshots.try_recv() {
+            self.status = status;
+        }
+        &self.status
+    }
+
+    /// Whether the latest status reflects every command sent, so a song
+    /// that just ended isn't taken for over again.
+    pub fn status_is_current(&mut self) -> bool {
+        self.status().commands == self.sent.get()
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.thread.is_finished()
+    }
+
+    /// Waits for the thread to end, returning the error of the final flush.
+    pub fn join(self) -> anyhow::Result<()> {
+        self.thread.join().expect("player thread to finish")
+    }
+}
+
+fn run(
+    mut player: SidPlayer,
+    commands: Receiver<Command>,
+    publisher: SyncSender<Status>,
+    limit: Option<Duration>,
+) -> anyhow::Result<()> {
+    let mut played = 0;
+    let mut handled = 0;
+    let mut published = Instant::now();
+    loop {
+        match commands.try_recv() {
+            Ok(Command::Quit) | Err(TryRecvError::Disconnected) => break,
+            Ok(command) => {
+                apply(&mut player, command);
+                handled += 1;
+                continue;
+            }
+            Err(TryRecvError::Empty) => {}
+        }
+
+        if limit.is_some_and(|limit| {
+            played >= (limit.as_secs_f64() * player.sample_rate() as f64) as usize
+        }) {
+            break;
+        }
+
+        let count = player.pump();
+        played += count / SidPlayer::CHANNELS as usize;
+
+        if published.elapsed() >= PlayerThread::STATUS_INTERVAL {
+            // A full channel means the UI hasn't read the previous snapshot
+            // yet; it gets a fresh one next time.
+            let _ = publisher.try_send(Status {
+                playing: player.is_playing(),
+                song: player.current_song(),
+                songs: player.songs(),
+                position: player.position(),
+                level: player.take_peak(),
+                ab_loop: player.ab_loop(),
+                silence: player.silence(),
+                commands: handled,
+            });
+            published = Instant::now();
+        }
+
+        if count == 0 {
+            thread::park_timeout(SidPlayer::IDLE_TIMEOUT);
+        }
+    }
+    player.finish()
+}
+
+fn apply(player: &mut SidPlayer, command: Command) {
+    match command {
+        Command::Load(sid_file) => player.load(&sid_file),
+        Command::Transition(sid_file, song) => player.transition_to(&sid_file, song),
+        Command::SetTransition(transition) => player.set_transition(transition),
+        Command::Play => player.play(),
+        Command::Pause => player.pause(),
+        Command::Stop => player.stop(),
+        Command::Next => player.next(),
+        Command::Prev => player.prev(),
+        Command::SelectSong(song) => player.select_song(song),
+        Command::Seek(position) => player.seek(position),
+        Command::Snapshot(reply) => {
+            let _ = reply.send(Box::new(player.snapshot()));
+        }
+        Command::Restore(snapshot) => player.restore(&snapshot),
+        Command::SetSink(sink) => player.set_sink(sink),
+        Command::SetVolume(volume) => player.set_volume(volume),
+        Command::SetNormalization(gain_db) => player.set_normalization(gain_db),
+        Command::SetSpeed(percent) => player.set_speed(percent),
+        Command::SetLoop(region) => player.set_loop(region),
+        Command::SetSilenceDetection(detection) => player.set_silence_detection(detection),
+        Command::Quit => {}
+    }
+}
@@ -0,0 +1,322 @@
+use crate::{player_thread::Status, random::Rng, songlength::DEFAULT_SONG_LENGTH};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+/// A subtune to play: the SID file by its path in the collection, as listed
+/// in `C64Music.json`, and the song number.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Entry {
+    pub path: String,
+    /// The start song of the tune when not given.
+    pub song: Option<u16>,
+}
+
+impl Entry {
+    pub fn new(path: impl Into<String>, song: Option<u16>) -> Self {
+        Self {
+            path: path.into(),
+            song,
+        }
+    }
+
+    /// File name and song, for lists.
+    pub fn title(&self) -> String {
+        let name = Path::new(&self.path)
+            .file_name()
+            .map_or(self.path.clone(), |name| {
+                name.to_string_lossy().into_owned()
+            });
+        match self.song {
+            Some(song) => format!("{name} #{song}"),
+            None => name,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum Repeat {
+    #[default]
+    Off,
+    /// The current entry over and over.
+    One,
+    /// The whole playlist, reshuffled on every round when shuffling.
+    All,
+}
+
+/// Ordered entries with a current one, plus a queue of entries to play
+/// before the playlist continues.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Playlist {
+    entries: Vec<Entry>,
+    pub repeat: Repeat,
+    shuffle: bool,
+    /// Index of the entry playing or played last.
+    #[serde(skip)]
+    current: Option<usize>,
+    /// Playing order as indices into `entries`. Rebuilt when it no longer
+    /// matches them.
+    #[serde(skip)]
+    order: Vec<usize>,
+    #[serde(skip)]
+    queue: VecDeque<Entry>,
+}
+
+impl Playlist {
+    pub const FILE_NAME: &str = "playlist.json";
+
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn current(&self) -> Option<usize> {
+        self.current
+    }
+
+    pub fn queue(&self) -> &VecDeque<Entry> {
+        &self.queue
+    }
+
+    pub fn shuffle(&self) -> bool {
+        self.shuffle
+    }
+
+    pub fn set_shuffle(&mut self, shuffle: bool) {
+        self.shuffle = shuffle;
+        self.order.clear();
+    }
+
+    pub fn add(&mut self, entry: Entry) {
+        self.insert(self.entries.len(), entry);
+    }
+
+    /// Inserts before `index`, or at the end when past it.
+    pub fn insert(&mut self, index: usize, entry: Entry) {
+        let index = index.min(self.entries.len());
+        self.entries.insert(index, entry);
+        self.current = self.current.map(|current| {
+            if current >= index {
+                current + 1
+            } else {
+                current
+            }
+        });
+        self.order.clear();
+    }
+
+    /// Removes the entry at `index`. Removing the current one makes the
+    /// entry after it play next.
+    pub fn remove(&mut self, index: usize) -> Option<Entry> {
+        if index >= self.entries.len() {
+            return None;
+        }
+        let entry = self.entries.remove(index);
+        self.current = match self.current {
+            Some(current) if current > index => Some(current - 1),
+            Some(current) if current == index => index.checked_sub(1),
+            current => current,
+        };
+        self.order.clear();
+        Some(entry)
+    }
+
+    /// Moves the entry at `from` so it ends up at `to`.
+    pub fn move_entry(&mut self, from: usize, to: usize) {
+        if from >= self.entries.len() || to >= self.entries.len() || from == to {
+            return;
+        }
+        let entry = self.entries.remove(from);
+        self.entries.insert(to, entry);
+        self.current = self.current.map(|current| match current {
+            current if current == from => to,
+            current if from < current && current <= to => current - 1,
+            current if to <= current && current < from => current + 1,
+            current => current,
+        });
+        self.order.clear();
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.current = None;
+        self.order.clear();
+    }
+
+    /// Plays `entry` after the current one, ahead of the playlist.
+    pub fn enqueue(&mut self, entry: Entry) {
+        self.queue.push_back(entry);
+    }
+
+    /// Makes the entry at `index` the current one.
+    pub fn select(&mut self, index: usize) -> Option<Entry> {
+        let entry = self.entries.get(index)?.clone();
+        self.current = Some(index);
+        Some(entry)
+    }
+
+    /// What plays after the current entry: the queue first, then the
+    /// playlist as the repeat mode and shuffling have it. `None` at the end.
+    pub fn advance(&mut self) -> Option<Entry> {
+        if let Some(entry) = self.queue.pop_front() {
+            return Some(entry);
+        }
+        if let (Repeat::One, Some(current)) = (self.repeat, self.current) {
+            return self.select(current);
+        }
+        let step = match self.position() {
+            Some(position) if position + 1 < self.order.len() => Some(position + 1),
+            Some(_) if self.repeat == Repeat::All => {
+                // A fresh order for every round.
+                self.current = None;
+                self.order.clear();
+                Some(0)
+            }
+            Some(_) => None,
+            None => Some(0),
+        };
+        let index = self.order().get(step?).copied()?;
+        self.select(index)
+    }
+
+    /// What played before the current entry, wrapping around when
+    /// repeating everything.
+    pub fn back(&mut self) -> Option<Entry> {
+        let step = match self.position() {
+            Some(0) if self.repeat == Repeat::All => self.order.len().checked_sub(1),
+            Some(position) => Some(position.saturating_sub(1)),
+            None => Some(0),
+        };
+        let index = self.order().get(step?).copied()?;
+        self.select(index)
+    }
+
+    /// Where the current entry is in the playing order.
+    fn position(&mut self) -> Option<usize> {
+        let current = self.current?;
+        self.order().iter().position(|&index| index == current)
+    }
+
+    fn order(&mut self) -> &[usize] {
+        if self.order.len() != self.entries.len() {
+            self.order = (0..self.entries.len()).collect();
+            if self.shuffle {
+                Rng::new().shuffle(&mut self.order);
+                // Keep going from where we are rather than repeating it.
+                if let Some(current) = self.current {
+                    let position = self.order.iter().position(|&index| index == current);
+                    if let Some(position) = position {
+                        self.order.swap(0, position);
+                    }
+                }
+            }
+        }
+        &self.order
+    }
+
+    /// Where the GUI keeps its playlist.
+    pub fn default_path() -> PathBuf {
+        crate::settings::config_dir().join(Self::FILE_NAME)
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let file =
+            std::fs::File::open(path).with_context(|| format!("can't open {}", path.display()))?;
+        Ok(serde_json::from_reader(std::io::BufReader::new(file))?)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let file = std::io::BufWriter::new(std::fs::File::create(path)?);
+        serde_json::to_writer_pretty(file, self)?;
+        Ok(())
+    }
+}
+
+/// Whether the song in `status` has played for its `length`, or for
+/// [`DEFAULT_SONG_LENGTH`] when that isn't known. A paused song or one
+/// being looped is never over.
+pub fn song_over(status: &Status, length: Option<Duration>) -> bool {
+    status.playing
+        && status.ab_loop.is_none()
+        && status.position >= length.unwrap_or(DEFAULT_SONG_LENGTH)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn three_entries(repeat: Repeat) -> Playlist {
+        let mut playlist = Playlist {
+            repeat,
+            ..Playlist::default()
+        };
+        for path in ["a.sid", "b.sid", "c.sid"] {
+            playlist.add(Entry::new(path, None));
+        }
+        playlist
+    }
+
+    fn path(entry: Option<Entry>) -> Option<String> {
+        entry.map(|entry| entry.path)
+    }
+
+    #[test]
+    fn advances_through_the_queue_first() {
+        let mut playlist = three_entries(Repeat::Off);
+        assert_eq!(path(playlist.advance()).as_deref(), Some("a.sid"));
+        playlist.enqueue(Entry::new("queued.sid", Some(2)));
+        assert_eq!(path(playlist.advance()).as_deref(), Some("queued.sid"));
+        assert_eq!(path(playlist.advance()).as_deref(), Some("b.sid"));
+        assert_eq!(path(playlist.advance()).as_deref(), Some("c.sid"));
+        assert_eq!(playlist.advance(), None);
+        assert_eq!(path(playlist.back()).as_deref(), Some("b.sid"));
+    }
+
+    #[test]
+    fn repeats() {
+        let mut playlist = three_entries(Repeat::All);
+        playlist.select(2);
+        assert_eq!(path(playlist.advance()).as_deref(), Some("a.sid"));
+        assert_eq!(path(playlist.back()).as_deref(), Some("c.sid"));
+
+        let mut playlist = three_entries(Repeat::One);
+        playlist.select(1);
+        assert_eq!(path(playlist.advance()).as_deref(), Some("b.sid"));
+    }
+
+    #[test]
+    fn shuffles_every_entry_once() {
+        let mut playlist = three_entries(Repeat::Off);
+        playlist.set_shuffle(true);
+        let mut played: Vec<_> = std::iter::from_fn(|| path(playlist.advance())).collect();
+        played.sort();
+        assert_eq!(played, ["a.sid", "b.sid", "c.sid"]);
+    }
+
+    #[test]
+    fn saves_and_loads() {
+        let path = std::env::temp_dir().join(format!("vicious-{}.json", std::process::id()));
+        let mut saved = three_entries(Repeat::All);
+        saved.set_shuffle(true);
+        saved.enqueue(Entry::new("queued.sid", None));
+        saved.save(&path).unwrap();
+        let loaded = Playlist::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.entries(), saved.entries());
+        assert_eq!(loaded.repeat, Repeat::All);
+        assert!(loaded.shuffle());
+        assert!(loaded.queue().is_empty());
+        assert_eq!(loaded.current(), None);
+    }
+}
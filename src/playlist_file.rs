@@ -0,0 +1,333 @@
+use crate::{
+    hvsc,
+    playlist::{Entry, Playlist},
+    songlength::SongLengths,
+};
+use anyhow::{bail, Context, Result};
+use sid_file::SidFile;
+use std::{
+    fmt::Write,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+/// Comment line carrying the subtune of the M3U entry that follows it.
+/// Other players skip it like any other comment.
+const M3U_SONG: &str = "#VICIOUS-SONG:";
+
+/// Playlist file formats, told apart by extension.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    /// The playlist files the GUI keeps its playlist in.
+    Json,
+    /// Extended M3U, always written as UTF-8 like M3U8.
+    M3u,
+    Pls,
+}
+
+impl Format {
+    pub fn of(path: &Path) -> Option<Self> {
+        let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+        match extension.as_str() {
+            "json" => Some(Self::Json),
+            "m3u" | "m3u8" => Some(Self::M3u),
+            "pls" => Some(Self::Pls),
+            _ => None,
+        }
+    }
+}
+
+/// Where entry paths point. Playlist entries are relative to `base`, the
+/// directory the collection is in; paths in playlist files may also be
+/// relative to the file itself or to the collection root, the way HVSC
+/// lists tunes (`/MUSICIANS/H/Hubbard_Rob/Commando.sid`).
+pub struct Locations<'a> {
+    pub base: &'a Path,
+    pub collection: &'a Path,
+}
+
+/// Entries read from a playlist file, and the paths that didn't lead to a
+/// file.
+#[derive(Debug, Default)]
+pub struct Import {
+    pub entries: Vec<Entry>,
+    pub unresolved: Vec<String>,
+}
+
+pub fn import(path: &Path, locations: &Locations) -> Result<Import> {
+    let Some(format) = Format::of(path) else {
+        bail!("{} isn't a known playlist format", path.display());
+    };
+    let entries = match format {
+        Format::Json => Playlist::load(path)?.entries().to_vec(),
+        Format::M3u | Format::Pls => {
+            let bytes =
+                std::fs::read(path).with_context(|| format!("can't read {}", path.display()))?;
+            // Plain `.m3u` files are often Latin-1, which this keeps legible.
+            let text = String::from_utf8(bytes)
+                .unwrap_or_else(|err| err.into_bytes().iter().map(|&b| b as char).collect());
+            let text = text.trim_start_matches('\u{feff}');
+            match format {
+                Format::Pls => parse_pls(text),
+                _ => parse_m3u(text),
+            }
+        }
+    };
+
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let mut import = Import::default();
+    for entry in entries {
+        match resolve(&entry.path, dir, locations) {
+            Some(path) => import.entries.push(Entry::new(path, entry.song)),
+            None => import.unresolved.push(entry.path),
+        }
+    }
+    Ok(import)
+}
+
+/// Writes `entries` in the format `path` asks for. M3U and PLS files get
+/// titles, and durations where the song-length database knows them.
+pub fn export(
+    path: &Path,
+    entries: &[Entry],
+    locations: &Locations,
+    lengths: &SongLengths,
+) -> Result<()> {
+    let Some(format) = Format::of(path) else {
+        bail!("{} isn't a known playlist format", path.display());
+    };
+    if format == Format::Json {
+        let mut playlist = Playlist::default();
+        for entry in entries {
+            playlist.add(entry.clone());
+        }
+        return playlist.save(path);
+    }
+
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let items = entries.iter().map(|entry| {
+        let file = locations.base.join(&entry.path);
+        let (title, length) = describe(&file, entry, lengths);
+        (relative_to(&file, dir), entry.song, title, length)
+    });
+    let mut text = String::new();
+    match format {
+        Format::Pls => {
+            writeln!(text, "[playlist]")?;
+            let mut count = 0;
+            for (number, (file, song, title, length)) in items.enumerate() {
+                let number = number + 1;
+                writeln!(text, "File{number}={file}")?;
+                writeln!(text, "Title{number}={title}")?;
+                writeln!(text, "Length{number}={}", seconds(length))?;
+                if let Some(song) = song {
+                    writeln!(text, "Song{number}={song}")?;
+                }
+                count = number;
+            }
+            writeln!(text, "NumberOfEntries={count}")?;
+            writeln!(text, "Version=2")?;
+        }
+        _ => {
+            writeln!(text, "#EXTM3U")?;
+            for (file, song, title, length) in items {
+                writeln!(text, "#EXTINF:{},{title}", seconds(length))?;
+                if let Some(song) = song {
+                    writeln!(text, "{M3U_SONG}{song}")?;
+                }
+                writeln!(text, "{file}")?;
+            }
+        }
+    }
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(path, text).with_context(|| format!("can't write {}", path.display()))
+}
+
+fn parse_m3u(text: &str) -> Vec<Entry> {
+    let mut entries = Vec::new();
+    let mut song = None;
+    for line in text.lines().map(str::trim).filter(|line| !line.is_empty()) {
+        if let Some(number) = line.strip_prefix(M3U_SONG) {
+            song = number.trim().parse().ok();
+        } else if !line.starts_with('#') {
+            entries.push(Entry::new(line, song.take()));
+        }
+    }
+    entries
+}
+
+/// Reads `FileN=` keys in order of N, with the subtune from `SongN=`.
+fn parse_pls(text: &str) -> Vec<Entry> {
+    let mut files = Vec::new();
+    let mut songs = Vec::new();
+    for line in text.lines() {
+        let Some((key, value)) = line.trim().split_once('=') else {
+            continue;
+        };
+        let key = key.trim().to_ascii_lowercase();
+        if let Some(Ok(number)) = key.strip_prefix("file").map(str::parse::<usize>) {
+            files.push((number, value.trim().to_owned()));
+        } else if let Some(Ok(number)) = key.strip_prefix("song").map(str::parse::<usize>) {
+            songs.push((number, value.trim().parse().ok()));
+        }
+    }
+    files.sort_by_key(|(number, _)| *number);
+    files
+        .into_iter()
+        .map(|(number, file)| {
+            let song = songs
+                .iter()
+                .find(|(song_number, _)| *song_number == number)
+                .and_then(|(_, song)| *song);
+            Entry::new(file, song)
+        })
+        .collect()
+}
+
+/// Finds the file `path` names, looking next to the playlist first,
+/// returning it relative to the base directory when it's inside.
+fn resolve(path: &str, dir: &Path, locations: &Locations) -> Option<String> {
+    if path.contains("://") {
+        return None;
+    }
+    let path = path.replace('\\', "/");
+    let relative = path.trim_start_matches('/');
+    let candidates = [
+        dir.join(&path),
+        locations.base.join(&path),
+        locations.collection.join(relative),
+    ];
+    let found = candidates
+        .into_iter()
+        .find(|candidate| candidate.is_file())?;
+    let found = found.strip_prefix(locations.base).unwrap_or(&found);
+    Some(found.to_string_lossy().into_owned())
+}
+
+/// `file` relative to `dir` when it's inside, with forward slashes.
+fn relative_to(file: &Path, dir: &Path) -> String {
+    let absolute = |path: &Path| std::path::absolute(path).unwrap_or(path.to_path_buf());
+    let file = absolute(file);
+    let path = file
+        .strip_prefix(absolute(dir))
+        .map(Path::to_path_buf)
+        .unwrap_or(file.clone());
+    path.to_string_lossy().replace('\\', "/")
+}
+
+/// `Author - Name` and song length of the entry's tune, as far as the file
+/// can be read.
+fn describe(file: &Path, entry: &Entry, lengths: &SongLengths) -> (String, Option<Duration>) {
+    let title = entry.title();
+    let Ok(data) = std::fs::read(file) else {
+        return (title, None);
+    };
+    let Ok(sid_file) = SidFile::parse(&data) else {
+        return (title, None);
+    };
+    let song = entry.song.unwrap_or(sid_file.start_song);
+    let full_md5 = hvsc::full_md5(&data);
+    let length = hvsc::md5(&data)
+        .ok()
+        .and_then(|md5| lengths.get_either(&md5, &full_md5, song));
+    let title = match entry.song {
+        Some(song) => format!("{} - {} #{song}", sid_file.author, sid_file.name),
+        None => format!("{} - {}", sid_file.author, sid_file.name),
+    };
+    (title, length)
+}
+
+/// Whole seconds, -1 for unknown as both formats have it.
+fn seconds(length: Option<Duration>) -> i64 {
+    length.map_or(-1, |length| length.as_secs_f64().round() as i64)
+}
+
+/// The collection root under `base`, where `download.sh` unpacks HVSC.
+pub fn collection_root(base: &Path) -> PathBuf {
+    base.join("C64Music")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_m3u() {
+        let text = "#EXTM3U\n\
+            #EXTINF:192,Rob Hubbard - Commando #2\n\
+            #VICIOUS-SONG:2\n\
+            MUSICIANS/H/Hubbard_Rob/Commando.sid\n\
+            \n\
+            # a comment\n\
+            /GAMES/A-F/Delta.sid\r\n";
+        assert_eq!(
+            parse_m3u(text),
+            [
+                Entry::new("MUSICIANS/H/Hubbard_Rob/Commando.sid", Some(2)),
+                Entry::new("/GAMES/A-F/Delta.sid", None),
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_pls_in_order_of_entries() {
+        let text = "[playlist]\n\
+            File2=/GAMES/A-F/Delta.sid\n\
+            Title2=Delta\n\
+            song1=3\n\
+            file1 = Commando.sid\n\
+            Song2=junk\n\
+            NumberOfEntries=2\n\
+            Version=2\n";
+        assert_eq!(
+            parse_pls(text),
+            [
+                Entry::new("Commando.sid", Some(3)),
+                Entry::new("/GAMES/A-F/Delta.sid", None),
+            ]
+        );
+    }
+
+    #[test]
+    fn tells_formats_by_extension() {
+        assert_eq!(Format::of(Path::new("a.M3U8")), Some(Format::M3u));
+        assert_eq!(Format::of(Path::new("a.pls")), Some(Format::Pls));
+        assert_eq!(Format::of(Path::new("a.json")), Some(Format::Json));
+        assert_eq!(Format::of(Path::new("a.sid")), None);
+    }
+
+    #[test]
+    fn exports_and_imports_files_and_songs() {
+        let base = std::env::temp_dir().join(format!("vicious-playlist-{}", std::process::id()));
+        let collection = collection_root(&base);
+        std::fs::create_dir_all(collection.join("GAMES")).unwrap();
+        std::fs::write(collection.join("GAMES/Delta.sid"), b"not a tune").unwrap();
+        let locations = Locations {
+            base: &base,
+            collection: &collection,
+        };
+        let entries = [
+            Entry::new("C64Music/GAMES/Delta.sid", Some(3)),
+            Entry::new("C64Music/GAMES/Delta.sid", None),
+        ];
+        for name in ["list.m3u", "list.pls", "list.json"] {
+            let path = base.join(name);
+            export(&path, &entries, &locations, &SongLengths::default()).unwrap();
+            let import = import(&path, &locations).unwrap();
+            assert_eq!(import.entries, entries, "{name}");
+            assert!(import.unresolved.is_empty());
+        }
+
+        let path = base.join("missing.m3u");
+        std::fs::write(&path, "/GAMES/Delta.sid\nGone.sid\n").unwrap();
+        let import = import(&path, &locations).unwrap();
+        std::fs::remove_dir_all(&base).unwrap();
+        assert_eq!(
+            import.entries,
+            [Entry::new("C64Music/GAMES/Delta.sid", None)]
+        );
+        assert_eq!(import.unresolved, ["Gone.sid"]);
+    }
+}
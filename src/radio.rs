@@ -0,0 +1,183 @@
+use crate::{
+    hvsc::{self, SidModel},
+    playlist::Entry,
+    random::Rng,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sid_file::SidFile;
+use std::collections::HashMap;
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// Which tunes the radio picks. Unset fields let everything through.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RadioFilter {
+    /// Part of the composer's name, in any case.
+    pub composer: String,
+    pub from_year: Option<u16>,
+    pub to_year: Option<u16>,
+    pub model: Option<SidModel>,
+    /// Skips tunes played within this many days.
+    pub not_played_days: Option<u32>,
+}
+
+impl RadioFilter {
+    fn accepts(&self, data: &[u8], sid_file: &SidFile, last_played: &HashMap<String, u64>) -> bool {
+        let composer = self.composer.trim().to_lowercase();
+        if !composer.is_empty() && !sid_file.author.to_lowercase().contains(&composer) {
+            return false;
+        }
+        if self.from_year.is_some() || self.to_year.is_some() {
+            let Some(year) = release_year(&sid_file.released) else {
+                return false;
+            };
+            if self.from_year.is_some_and(|from| year < from)
+                || self.to_year.is_some_and(|to| year > to)
+            {
+                return false;
+            }
+        }
+        if self
+            .model
+            .is_some_and(|model| !SidModel::of(data, sid_file).suits(model))
+        {
+            return false;
+        }
+        match (self.not_played_days, hvsc::md5(data)) {
+            (Some(days), Ok(md5)) => last_played.get(&md5).is_none_or(|&started| {
+                unix_time().saturating_sub(started) / SECONDS_PER_DAY >= days as u64
+            }),
+            _ => true,
+        }
+    }
+
+    /// Whether a collection path may belong to the composer. HVSC names
+    /// directories `Hubbard_Rob`, so this only narrows down the candidates.
+    fn may_match_path(&self, path: &str) -> bool {
+        let path = path.to_lowercase().replace('_', " ");
+        self.composer
+            .to_lowercase()
+            .split_whitespace()
+            .all(|word| path.contains(word))
+    }
+}
+
+/// The year a `released` header field starts with, e.g. 1986 of
+/// `1986 Firebird`.
+pub(crate) fn release_year(released: &str) -> Option<u16> {
+    released.get(..4)?.parse().ok()
+}
+
+/// Endless random play: picks a tune matching the filter and one of its
+/// subtunes, for as long as it's asked to.
+pub struct Radio {
+    paths: Vec<String>,
+    filter: RadioFilter,
+    /// Indices into `paths` worth trying for the filter.
+    candidates: Vec<usize>,
+    rng: Rng,
+}
+
+impl Radio {
+    /// Files tried per pick before giving up on a filter nothing matches.
+    const ATTEMPTS: usize = 500;
+
+    pub fn new(paths: Vec<String>, filter: RadioFilter) -> Self {
+        let mut radio = Self {
+            paths,
+            filter: RadioFilter::default(),
+            candidates: Vec::new(),
+            rng: Rng::new(),
+        };
+        radio.set_filter(filter);
+        radio
+    }
+
+    pub fn filter(&self) -> &RadioFilter {
+        &self.filter
+    }
+
+    pub fn set_filter(&mut self, filter: RadioFilter) {
+        let by_path: Vec<usize> = (0..self.paths.len())
+            .filter(|&index| filter.may_match_path(&self.paths[index]))
+            .collect();
+        // Composers may be credited under another name than their
+        // directory, so fall back to reading every file.
+        self.candidates = if by_path.is_empty() {
+            (0..self.paths.len()).collect()
+        } else {
+            by_path
+        };
+        self.filter = filter;
+    }
+
+    /// A random subtune of a random tune passing the filter, reading files
+    /// by collection path with `read`. `last_played` is
+    /// [`History::last_played`](crate::history::History::last_played).
+    /// `None` when nothing was found.
+    pub fn pick(
+        &mut self,
+        read: impl Fn(&str) -> Option<Vec<u8>>,
+        last_played: &HashMap<String, u64>,
+    ) -> Option<Entry> {
+        if self.candidates.is_empty() {
+            return None;
+        }
+        for _ in 0..Self::ATTEMPTS {
+            let index = self.candidates[self.rng.below(self.candidates.len())];
+            let path = &self.paths[index];
+            let Some(data) = read(path) else {
+                continue;
+            };
+            let Ok(sid_file) = SidFile::parse(&data) else {
+                continue;
+            };
+            if sid_file.songs == 0 || !self.filter.accepts(&data, &sid_file, last_played) {
+                continue;
+            }
+            let song = self.rng.below(sid_file.songs as usize) as u16 + 1;
+            return Some(Entry::new(path.clone(), Some(song)));
+        }
+        None
+    }
+}
+
+/// Collection paths of the files in a `C64Music.json` tree.
+pub fn tree_paths(tree: &Value) -> Vec<String> {
+    let mut paths = Vec::new();
+    collect_paths(tree, &mut paths);
+    paths
+}
+
+fn collect_paths(node: &Value, paths: &mut Vec<String>) {
+    match node.get("type").and_then(Value::as_str) {
+        Some("file") => {
+            if let Some(path) = node.get("path").and_then(Value::as_str) {
+                paths.push(path.to_owned());
+            }
+        }
+        Some("directory") => {
+            let children = node.get("children").and_then(Value::as_array);
+            for child in children.into_iter().flatten() {
+                collect_paths(child, paths);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Seconds since the Unix epoch.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn unix_time() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
+
+/// Seconds since the Unix epoch. `SystemTime` isn't available in browsers.
+#[cfg(target_arch = "wasm32")]
+pub fn unix_time() -> u64 {
+    (web_sys::js_sys::Date::now() / 1000.0) as u64
+}
@@ -0,0 +1,36 @@
+use std::{collections::hash_map::RandomState, hash::BuildHasher};
+
+/// Xorshift generator, good enough for shuffling and picking tunes. Seeded
+/// from the hasher keys, which std draws from the system's random source.
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new() -> Self {
+        Self(RandomState::new().hash_one(0u64) | 1)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// Uniform enough in `0..bound` for bounds far below 2^64.
+    pub fn below(&mut self, bound: usize) -> usize {
+        (self.next_u64() % bound.max(1) as u64) as usize
+    }
+
+    /// Fisher-Yates.
+    pub fn shuffle<T>(&mut self, items: &mut [T]) {
+        for index in (1..items.len()).rev() {
+            items.swap(index, self.below(index + 1));
+        }
+    }
+}
+
+impl Default for Rng {
+    fn default() -> Self {
+        Self::new()
+    }
+}
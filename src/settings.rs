@@ -0,0 +1,95 @@
+use crate::{
+    loudness::Normalization, output::OutputSelection, radio::RadioFilter,
+    silence::SilenceDetection, sink::SinkConfig, transition::Transition, volume::Volume,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+/// User settings persisted between runs in `settings.json` under the user
+/// config directory (`$XDG_CONFIG_HOME/vicious` or `~/.config/vicious`).
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    pub output: OutputSelection,
+    pub sink: SinkConfig,
+    pub volume: Volume,
+    pub normalization: Normalization,
+    pub radio: RadioFilter,
+    pub transition: Transition,
+    pub silence: SilenceDetection,
+}
+
+impl Settings {
+    pub fn load() -> Self {
+        load_json("settings.json")
+    }
+
+    pub fn save(&self) -> anyhow::Result<()> {
+        save_json("settings.json", self)
+    }
+}
+
+/// Reads `name` from [`config_dir`], falling back to the default when the
+/// file is missing. A file that can't be parsed is logged and moved aside
+/// to `<name>.bad` so the next save doesn't overwrite it.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn load_json<T: DeserializeOwned + Default>(name: &str) -> T {
+    let path = config_dir().join(name);
+    let file = match std::fs::File::open(&path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return T::default(),
+        Err(err) => {
+            log::error!("Failed to open {}: {err}", path.display());
+            return T::default();
+        }
+    };
+    match serde_json::from_reader(std::io::BufReader::new(file)) {
+        Ok(value) => value,
+        Err(err) => {
+            log::error!("Failed to parse {}: {err}", path.display());
+            let bad = path.with_extension("json.bad");
+            if let Err(err) = std::fs::rename(&path, &bad) {
+                log::error!("Failed to move {} aside: {err}", path.display());
+            }
+            T::default()
+        }
+    }
+}
+
+/// Writes `value` to `name` in [`config_dir`]. The JSON goes to a temporary
+/// file first and is renamed over the target, so a crash mid-write leaves
+/// the previous file intact.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn save_json<T: Serialize>(name: &str, value: &T) -> anyhow::Result<()> {
+    use std::io::Write;
+
+    let dir = config_dir();
+    std::fs::create_dir_all(&dir)?;
+    let temp = dir.join(format!("{name}.tmp"));
+    let mut writer = std::io::BufWriter::new(std::fs::File::create(&temp)?);
+    serde_json::to_writer_pretty(&mut writer, value)?;
+    writer.flush()?;
+    writer.into_inner()?.sync_all()?;
+    std::fs::rename(&temp, dir.join(name))?;
+    Ok(())
+}
+
+#[cfg(target_arch = "wasm32")]
+pub fn load_json<T: DeserializeOwned + Default>(_name: &str) -> T {
+    T::default()
+}
+
+#[cfg(target_arch = "wasm32")]
+pub fn save_json<T: Serialize>(_name: &str, _value: &T) -> anyhow::Result<()> {
+    Ok(())
+}
+
+pub fn config_dir() -> std::path::PathBuf {
+    use std::{env, path::PathBuf};
+
+    let base = env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("APPDATA").map(PathBuf::from))
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+        .unwrap_or_else(|| PathBuf::from("."));
+    base.join("vicious")
+}
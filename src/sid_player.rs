@@ -1,45 +1,119 @@
-use log::info;
+use crate::{
+    ab_loop::LoopRegion,
+    silence::{SilenceDetection, SilenceDetector},
+    sink::{NullSink, Sink},
+    snapshot::{CpuState, SidState, Snapshot},
+    transition::{Fade, Transition, TransitionMode},
+    volume::{Gain, Volume},
+};
+use log::{error, info};
 use mos6510rs::{Registers, StatusFlags, CPU};
-use rb::{Producer, RbProducer};
 use resid::{SamplingMethod, Sid};
-use std::sync::{Arc, Mutex};
+use ringbuf::{
+    traits::{Consumer, Producer, Split},
+    HeapCons, HeapRb,
+};
+use sid_file::SidFile;
+use std::{cell::RefCell, time::Duration};
 
 pub struct SidPlayer {
     cpu: CPU,
-    sid: Arc<Mutex<Sid>>,
+    sid: Sid,
+    /// SID register writes made by the CPU, applied after every instruction.
+    sid_writes: HeapCons<(u8, u8)>,
     init_address: u16,
     play_address: u16,
     songs: u16,
     current_song: u16,
-    producer: Producer<i16>,
+    sink: Box<dyn Sink>,
+    sample_rate: u32,
+    buffer: Vec<i16>,
+    gain: Gain,
+    /// Tempo in percent: how often the play routine runs relative to
+    /// emulated time. The SID keeps its clock, so pitch doesn't change.
+    speed: u32,
+    cycles_to_frame: u32,
+    /// Emulated cycles since the current song was started.
+    cycles: u64,
+    peak: i16,
     playing: bool,
+    /// Paused rather than stopped: the sink keeps what it has buffered and
+    /// nothing is rendered until resumed.
+    paused: bool,
+    /// Taken every [`Self::SNAPSHOT_INTERVAL`] of the current song, oldest
+    /// first, so seeking back doesn't have to replay from the start.
+    snapshots: Vec<Snapshot>,
+    ab_loop: Option<ActiveLoop>,
+    transition: Transition,
+    /// The previous tune while it fades out.
+    fade: Option<Fade>,
+    silence: SilenceDetector,
+}
+
+/// A [`LoopRegion`] being played, with the state to jump back to at B.
+struct ActiveLoop {
+    region: LoopRegion,
+    start: Snapshot,
+    end_cycles: u64,
 }
 
 impl SidPlayer {
-    pub fn new(producer: Producer<i16>, sample_rate: u32) -> Self {
+    pub const CHANNELS: u16 = 1;
+    const CLOCK_FREQUENCY: u32 = 985_248;
+    /// Cycles between play routine calls: one PAL frame (312 lines of 63 cycles).
+    const FRAME_CYCLES: u32 = 312 * 63;
+    const BUFFER_SIZE: usize = 2i32.pow(13) as usize;
+    /// More than a single instruction can write (read-modify-write ones
+    /// write twice).
+    const SID_WRITES_SIZE: usize = 16;
+    /// Longest the player thread sleeps between asking the sink for demand.
+    /// Device sinks wake it earlier, as soon as the sound card has read.
+    pub const IDLE_TIMEOUT: Duration = Duration::from_millis(5);
+    const SNAPSHOT_INTERVAL: u64 = 5 * Self::CLOCK_FREQUENCY as u64;
+    /// Ten minutes of snapshots, about 70K each. Seeking further replays
+    /// from the last one.
+    const MAX_SNAPSHOTS: usize = 120;
+    pub const NORMAL_SPEED: u32 = 100;
+    pub const MIN_SPEED: u32 = 25;
+    pub const MAX_SPEED: u32 = 400;
+
+    pub fn new(sink: Box<dyn Sink>) -> Self {
+        let sample_rate = sink.sample_rate();
         let mut sid = Sid::new(resid::ChipModel::Mos8580);
-        sid.set_sampling_parameters(SamplingMethod::Fast, 985_248, sample_rate);
+        sid.set_sampling_parameters(SamplingMethod::Fast, Self::CLOCK_FREQUENCY, sample_rate);
         sid.write(24, 15);
 
-        let sid = Arc::new(Mutex::new(sid));
-
         let mut cpu = CPU::new();
 
-        let cpu_sid = sid.clone();
+        let (producer, sid_writes) = HeapRb::new(Self::SID_WRITES_SIZE).split();
+        let producer = RefCell::new(producer);
         cpu.set_write_byte_callback(Box::new(move |address, value| {
             if (address & 0xfc00) == 0xd400 {
-                cpu_sid
-                    .lock()
-                    .expect("to lock")
-                    .write((address & 0x1f) as u8, value);
+                let _ = producer
+                    .borrow_mut()
+                    .try_push(((address & 0x1f) as u8, value));
             }
         }));
 
         Self {
             sid,
+            sid_writes,
             cpu,
-            producer,
+            sink,
+            sample_rate,
+            buffer: vec![0; Self::BUFFER_SIZE],
+            gain: Gain::new(Volume::default()),
+            speed: Self::NORMAL_SPEED,
+            cycles_to_frame: 0,
+            cycles: 0,
+            peak: 0,
             playing: false,
+            paused: false,
+            snapshots: Vec::new(),
+            ab_loop: None,
+            transition: Transition::default(),
+            fade: None,
+            silence: SilenceDetector::new(SilenceDetection::default()),
             init_address: 0,
             play_address: 0,
             songs: 0,
@@ -47,6 +121,92 @@ impl SidPlayer {
         }
     }
 
+    /// Replaces the sink, e.g. when the user picks another output.
+    pub fn set_sink(&mut self, sink: Box<dyn Sink>) {
+        self.sink = sink;
+        self.sink.set_paused(self.paused);
+    }
+
+    pub fn set_volume(&mut self, volume: Volume) {
+        self.gain.set_volume(volume);
+    }
+
+    /// Extra gain in dB bringing the current song to the target loudness.
+    pub fn set_normalization(&mut self, gain_db: f32) {
+        self.gain.set_normalization(gain_db);
+    }
+
+    /// Tempo in percent, clamped to [`Self::MIN_SPEED`]..=[`Self::MAX_SPEED`].
+    /// Takes effect from the next play routine call.
+    pub fn set_speed(&mut self, percent: u32) {
+        self.speed = percent.clamp(Self::MIN_SPEED, Self::MAX_SPEED);
+    }
+
+    pub fn speed(&self) -> u32 {
+        self.speed
+    }
+
+    /// Cycles from one play routine call to the next at the current speed.
+    fn frame_cycles(&self) -> u32 {
+        Self::FRAME_CYCLES * Self::NORMAL_SPEED / self.speed
+    }
+
+    /// Flushes the sink once playback is over.
+    pub fn finish(&mut self) -> anyhow::Result<()> {
+        self.sink.finish()
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.sample_rate = sample_rate;
+        self.sid
+            .set_sampling_parameters(SamplingMethod::Fast, Self::CLOCK_FREQUENCY, sample_rate);
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.playing
+    }
+
+    pub fn current_song(&self) -> u16 {
+        self.current_song
+    }
+
+    pub fn songs(&self) -> u16 {
+        self.songs
+    }
+
+    /// Emulated time since the current song was started.
+    pub fn position(&self) -> Duration {
+        Self::cycles_to_time(self.cycles)
+    }
+
+    fn time_to_cycles(time: Duration) -> u64 {
+        time.as_micros() as u64 * Self::CLOCK_FREQUENCY as u64 / 1_000_000
+    }
+
+    pub(crate) fn cycles_to_time(cycles: u64) -> Duration {
+        Duration::from_micros(cycles * 1_000_000 / Self::CLOCK_FREQUENCY as u64)
+    }
+
+    /// Highest absolute sample value rendered since the last call, 0.0 to 1.0.
+    pub fn take_peak(&mut self) -> f32 {
+        std::mem::take(&mut self.peak) as f32 / i16::MAX as f32
+    }
+
+    pub fn load(&mut self, sid_file: &SidFile) {
+        self.load_data(
+            &sid_file.data,
+            sid_file.real_load_address,
+            sid_file.init_address,
+            sid_file.play_address,
+            sid_file.songs,
+            sid_file.start_song,
+        );
+    }
+
     pub fn load_data(
         &mut self,
         data: &[u8],
@@ -55,11 +215,27 @@ impl SidPlayer {
         play_address: u16,
         songs: u16,
         current_song: u16,
+    ) {
+        self.fade = None;
+        self.install(data, load_address, init_addres, play_address, songs);
+        self.current_song = current_song;
+        self.resume_sink();
+        self.change_track(self.current_song);
+        self.playing = true;
+    }
+
+    /// Puts the tune in memory without starting a song.
+    fn install(
+        &mut self,
+        data: &[u8],
+        load_address: u16,
+        init_addres: u16,
+        play_address: u16,
+        songs: u16,
     ) {
         self.init_address = init_addres;
         self.play_address = play_address;
         self.songs = songs;
-        self.current_song = current_song;
 
         self.cpu.write_slice(data, load_address);
 
@@ -69,56 +245,338 @@ impl SidPlayer {
             self.play_address = self.cpu.read_word(0x0314);
             info!("new play address == {}", &self.play_address);
         }
+    }
 
-        self.change_track(self.current_song);
-        self.playing = true;
+    pub fn set_transition(&mut self, transition: Transition) {
+        self.transition = transition;
     }
 
-    const BUFFER_SIZE: usize = 2i32.pow(13) as usize;
+    pub fn set_silence_detection(&mut self, detection: SilenceDetection) {
+        self.silence.configure(detection);
+    }
 
-    pub fn step(&mut self) {
-        if !self.playing {
+    /// How long the output of the current song has been silent.
+    pub fn silence(&self) -> Duration {
+        self.silence.silence(self.sample_rate)
+    }
+
+    /// Moves on to `song` of another tune (its start song when `None`) the
+    /// way the transition setting asks for. A stopped player just loads it.
+    pub fn transition_to(&mut self, sid_file: &SidFile, song: Option<u16>) {
+        let song = song
+            .filter(|&song| song > 0 && song <= sid_file.songs)
+            .unwrap_or(sid_file.start_song);
+        if self.transition.mode == TransitionMode::Cut || !self.playing {
+            self.load(sid_file);
+            if song != sid_file.start_song {
+                self.select_song(song);
+            }
             return;
         }
 
-        if self.playing && 0 == self.jump_subroutine(self.play_address, 0) {
-            self.playing = false;
+        self.fade = match self.transition.mode {
+            TransitionMode::Fade | TransitionMode::Crossfade => Some(Fade::new(
+                self.outgoing(),
+                self.transition,
+                self.sample_rate,
+            )),
+            _ => None,
+        };
+        self.install(
+            &sid_file.data,
+            sid_file.real_load_address,
+            sid_file.init_address,
+            sid_file.play_address,
+            sid_file.songs,
+        );
+        self.current_song = song;
+        self.start_track(song);
+    }
+
+    /// A player of its own continuing the current tune, to fade it out.
+    fn outgoing(&self) -> SidPlayer {
+        let mut outgoing = SidPlayer::new(Box::new(NullSink::new(false)));
+        outgoing.restore(&self.snapshot());
+        outgoing.set_sample_rate(self.sample_rate);
+        outgoing.speed = self.speed;
+        outgoing.playing = true;
+        outgoing
+    }
+
+    /// Asks the sink how many samples it wants and renders them. Returns the
+    /// number of samples written, 0 when the sink doesn't need any yet.
+    pub fn pump(&mut self) -> usize {
+        let sample_rate = self.sink.sample_rate();
+        if sample_rate != self.sample_rate {
+            self.set_sample_rate(sample_rate);
+        }
+
+        if self.paused {
+            return 0;
+        }
+        let count = self.sink.demand().min(Self::BUFFER_SIZE);
+        if count == 0 {
+            return 0;
+        }
+
+        let mut buffer = std::mem::take(&mut self.buffer);
+        match self.fade.take() {
+            Some(mut fade) => {
+                fade.render(self, &mut buffer[..count]);
+                if !fade.is_done() {
+                    self.fade = Some(fade);
+                }
+            }
+            None => self.render(&mut buffer[..count]),
+        }
+        // Before the gain, so turning the volume down isn't silence.
+        self.silence.process(&buffer[..count], self.sample_rate);
+        self.gain.process(&mut buffer[..count], self.sample_rate);
+        let peak = buffer[..count]
+            .iter()
+            .map(|sample| sample.saturating_abs())
+            .max()
+            .unwrap_or(0);
+        self.peak = self.peak.max(peak);
+        if let Err(err) = self.sink.write(&buffer[..count]) {
+            error!("can't write samples: {err}");
         }
+        self.buffer = buffer;
+        count
+    }
 
-        let mut delta: u32 = 20000;
-        let mut buffer = vec![0; Self::BUFFER_SIZE];
+    /// Fills `buffer` with emulated output. The play routine is called every
+    /// [`Self::FRAME_CYCLES`] emulated cycles, scaled by the speed, so its
+    /// rate follows the samples actually consumed rather than a wall clock.
+    pub fn render(&mut self, buffer: &mut [i16]) {
         let mut samples_count = 0;
-        while delta > 0 {
-            let (samples, next_delta) =
-                self.sid
-                    .lock()
-                    .expect("to lock")
-                    .sample(delta, &mut buffer[samples_count..], 1);
+        while samples_count < buffer.len() {
+            if !self.playing {
+                buffer[samples_count..].fill(0);
+                return;
+            }
+
+            if let Some(ab_loop) = self.ab_loop.take() {
+                if self.cycles >= ab_loop.end_cycles {
+                    self.load_snapshot(&ab_loop.start);
+                }
+                self.ab_loop = Some(ab_loop);
+            }
+
+            if self.cycles_to_frame == 0 {
+                self.auto_snapshot();
+                if 0 == self.jump_subroutine(self.play_address, 0) {
+                    self.playing = false;
+                }
+                self.cycles_to_frame = self.frame_cycles();
+            }
+
+            // Stop exactly at B so the jump back to A is seamless.
+            let cycles = match &self.ab_loop {
+                Some(ab_loop) => self.cycles_to_frame.min(
+                    ab_loop
+                        .end_cycles
+                        .saturating_sub(self.cycles)
+                        .min(u32::MAX as u64) as u32,
+                ),
+                None => self.cycles_to_frame,
+            };
+            let (samples, remaining) = self.sid.sample(cycles, &mut buffer[samples_count..], 1);
             samples_count += samples;
-            delta = next_delta;
+            self.cycles += (cycles - remaining) as u64;
+            self.cycles_to_frame -= cycles - remaining;
         }
-        //TODO
-        let _ = self.producer.write_blocking(&buffer[..samples_count]);
     }
 
+    /// Moves to `position` in the current song: jumps to the closest
+    /// snapshot before it, then runs the emulation without rendering
+    /// samples for the rest. Without snapshots seeking back restarts the
+    /// song.
+    pub fn seek(&mut self, position: Duration) {
+        let target = Self::time_to_cycles(position);
+        let closest = self
+            .snapshots
+            .iter()
+            .rev()
+            .find(|snapshot| snapshot.cycles <= target)
+            .filter(|snapshot| target < self.cycles || snapshot.cycles > self.cycles)
+            .cloned();
+        match closest {
+            Some(snapshot) => self.apply_snapshot(&snapshot),
+            None if target < self.cycles => self.change_track(self.current_song),
+            None => self.sink.flush(),
+        }
+
+        while self.cycles < target {
+            if self.cycles_to_frame == 0 {
+                self.auto_snapshot();
+                self.jump_subroutine(self.play_address, 0);
+                self.cycles_to_frame = self.frame_cycles();
+            }
+            let delta = self
+                .cycles_to_frame
+                .min((target - self.cycles).min(u32::MAX as u64) as u32);
+            self.sid.clock_delta(delta);
+            self.cycles += delta as u64;
+            self.cycles_to_frame -= delta;
+        }
+        self.silence.reset();
+    }
+
+    /// Loops `region` of the current song, starting at A right away, or
+    /// stops looping on `None` or a region not ending after it starts.
+    /// Jumping back restores a snapshot taken at A, so the loop is exact.
+    pub fn set_loop(&mut self, region: Option<LoopRegion>) {
+        self.ab_loop = None;
+        let Some(region) = region.filter(LoopRegion::is_valid) else {
+            return;
+        };
+        self.seek(region.start);
+        self.ab_loop = Some(ActiveLoop {
+            region,
+            start: self.snapshot(),
+            end_cycles: Self::time_to_cycles(region.end),
+        });
+    }
+
+    pub fn ab_loop(&self) -> Option<LoopRegion> {
+        self.ab_loop.as_ref().map(|ab_loop| ab_loop.region)
+    }
+
+    pub fn select_song(&mut self, song: u16) {
+        if song > 0 && song <= self.songs {
+            self.current_song = song;
+            self.change_track(song);
+        }
+    }
+
+    /// Restarts at `track`, dropping what the sink buffered of the old one
+    /// so the new one is heard right away.
     pub fn change_track(&mut self, track: u16) {
         if track > 0 && track <= self.songs {
+            self.sink.flush();
+            self.fade = None;
+            self.start_track(track);
+        }
+    }
+
+    /// Runs the init routine for `track`, keeping what the sink buffered.
+    fn start_track(&mut self, track: u16) {
+        if track > 0 && track <= self.songs {
+            self.ab_loop = None;
+            self.cycles_to_frame = 0;
+            self.cycles = 0;
+            self.silence.reset();
             self.cpu.reset();
             self.jump_subroutine(self.init_address, (track - 1) as u8);
+            self.snapshots.clear();
+            self.auto_snapshot();
+        }
+    }
+
+    /// Runs the play routine once and moves on to the next call without
+    /// clocking the SID, which is all analyzing the player needs. `false`
+    /// once the routine no longer returns.
+    pub(crate) fn skip_frame(&mut self) -> bool {
+        if !self.playing || self.jump_subroutine(self.play_address, 0) == 0 {
+            self.playing = false;
+            return false;
+        }
+        self.cycles += self.frame_cycles() as u64;
+        true
+    }
+
+    /// The C64 memory, SID registers included as last written.
+    pub(crate) fn memory(&self) -> &[u8] {
+        &self.cpu.memory[..]
+    }
+
+    /// Current state, e.g. for saving to disk.
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            memory: self.cpu.memory.to_vec(),
+            cpu: CpuState::new(&self.cpu.registers, &self.cpu.status_flags),
+            sid: SidState::new(&self.sid),
+            sid_copy: Some(Box::new(self.sid.clone())),
+            init_address: self.init_address,
+            play_address: self.play_address,
+            songs: self.songs,
+            song: self.current_song,
+            cycles: self.cycles,
+            cycles_to_frame: self.cycles_to_frame,
+        }
+    }
+
+    /// Continues from `snapshot`, which may come from another tune.
+    pub fn restore(&mut self, snapshot: &Snapshot) {
+        self.snapshots.clear();
+        self.ab_loop = None;
+        self.apply_snapshot(snapshot);
+        self.snapshots.push(snapshot.clone());
+    }
+
+    fn apply_snapshot(&mut self, snapshot: &Snapshot) {
+        self.sink.flush();
+        self.load_snapshot(snapshot);
+    }
+
+    /// Like [`Self::apply_snapshot`], but keeps what the sink buffered.
+    fn load_snapshot(&mut self, snapshot: &Snapshot) {
+        self.cpu.memory.copy_from_slice(&snapshot.memory);
+        snapshot
+            .cpu
+            .restore(&mut self.cpu.registers, &mut self.cpu.status_flags);
+        match &snapshot.sid_copy {
+            Some(sid) => self.sid = (**sid).clone(),
+            None => snapshot.sid.restore(&mut self.sid),
+        }
+        self.set_sample_rate(self.sample_rate);
+        self.init_address = snapshot.init_address;
+        self.play_address = snapshot.play_address;
+        self.songs = snapshot.songs;
+        self.current_song = snapshot.song;
+        self.cycles = snapshot.cycles;
+        self.cycles_to_frame = snapshot.cycles_to_frame;
+    }
+
+    /// Takes a snapshot when the last one is [`Self::SNAPSHOT_INTERVAL`] old.
+    fn auto_snapshot(&mut self) {
+        let due = self
+            .snapshots
+            .last()
+            .is_none_or(|last| self.cycles >= last.cycles + Self::SNAPSHOT_INTERVAL);
+        if due && self.snapshots.len() < Self::MAX_SNAPSHOTS {
+            self.snapshots.push(self.snapshot());
         }
     }
 
     pub fn play(&mut self) {
+        self.resume_sink();
         self.playing = true;
     }
 
+    /// Stops and rewinds the song, dropping what the sink still buffers.
     pub fn stop(&mut self) {
+        self.resume_sink();
         self.playing = false;
         self.change_track(self.current_song);
     }
 
+    /// Pauses with the sink holding on to its buffered audio, which plays
+    /// on [`Self::play`].
     pub fn pause(&mut self) {
-        self.playing = false;
+        if self.playing {
+            self.playing = false;
+            self.paused = true;
+            self.sink.set_paused(true);
+        }
+    }
+
+    fn resume_sink(&mut self) {
+        if self.paused {
+            self.paused = false;
+            self.sink.set_paused(false);
+        }
     }
 
     pub fn next(&mut self) {
@@ -153,7 +611,75 @@ impl SidPlayer {
         while self.cpu.registers.program_counter > 1 {
             let step_count = self.cpu.step();
             cycles += step_count;
+            for (register, value) in self.sid_writes.pop_iter() {
+                self.sid.write(register, value);
+            }
         }
         cycles
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sink::MemorySink;
+
+    /// Init returns right away, play counts its calls at $2000.
+    const TUNE: [u8; 5] = [0x60, 0xee, 0x00, 0x20, 0x60];
+
+    /// Pumps `pumps` times at `speed`, returning the samples the sink got
+    /// and the play routine calls.
+    fn pump(speed: u32, pumps: usize) -> (Vec<i16>, u8, SidPlayer) {
+        let sink = MemorySink::default();
+        let mut player = SidPlayer::new(Box::new(sink.clone()));
+        player.set_speed(speed);
+        player.load_data(&TUNE, 0x1000, 0x1000, 0x1001, 1, 1);
+        for _ in 0..pumps {
+            assert_eq!(player.pump(), 4096);
+        }
+        let calls = player.memory()[0x2000];
+        (sink.take(), calls, player)
+    }
+
+    #[test]
+    fn pump_writes_what_the_sink_demands() {
+        let (samples, calls, player) = pump(SidPlayer::NORMAL_SPEED, 11);
+        assert_eq!(samples.len(), 11 * 4096);
+        // 45056 samples at 44.1 kHz, one call every PAL frame.
+        let position = player.position().as_secs_f64();
+        assert!((position - 45056.0 / 44100.0).abs() < 0.001, "{position}");
+        assert_eq!(calls, (position * 985_248.0 / 19656.0).ceil() as u8);
+    }
+
+    #[test]
+    fn speed_changes_calls_not_samples() {
+        let (normal, normal_calls, normal_player) = pump(SidPlayer::NORMAL_SPEED, 11);
+        for (speed, expected) in [(50, normal_calls / 2), (200, normal_calls * 2)] {
+            let (samples, calls, player) = pump(speed, 11);
+            assert_eq!(samples.len(), normal.len());
+            assert_eq!(player.position(), normal_player.position());
+            assert!(calls.abs_diff(expected) <= 1, "{speed}%: {calls} calls");
+        }
+    }
+
+    #[test]
+    fn paused_player_writes_nothing() {
+        let (_, _, mut player) = pump(SidPlayer::NORMAL_SPEED, 1);
+        player.pause();
+        assert_eq!(player.pump(), 0);
+        player.play();
+        assert_eq!(player.pump(), 4096);
+    }
+
+    #[test]
+    fn ignores_loops_ending_before_they_start() {
+        let (_, _, mut player) = pump(SidPlayer::NORMAL_SPEED, 1);
+        let region = LoopRegion {
+            start: Duration::from_secs(2),
+            end: Duration::from_secs(1),
+        };
+        player.set_loop(Some(region));
+        assert_eq!(player.ab_loop(), None);
+        assert_eq!(player.pump(), 4096);
+    }
+}
@@ -0,0 +1,118 @@
+use crate::player_thread::Status;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// What happens once a song has been silent for long enough.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum SilenceAction {
+    /// The next subtune, or the next tune after the last one.
+    #[default]
+    NextSong,
+    NextTune,
+}
+
+/// Skipping songs that went quiet: sound effects, tunes that end, and
+/// tunes without a song length that would otherwise play silence forever.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SilenceDetection {
+    pub enabled: bool,
+    /// Level in dBFS below which the output counts as silent.
+    pub threshold_db: f32,
+    /// How long it has to stay below the threshold.
+    pub duration_ms: u32,
+    /// Measures the level around the DC offset rather than around zero.
+    /// The 6581 outputs a constant offset that never drops below any
+    /// useful threshold on its own.
+    pub ignore_dc: bool,
+    pub action: SilenceAction,
+}
+
+impl Default for SilenceDetection {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            threshold_db: -60.0,
+            duration_ms: 5000,
+            ignore_dc: true,
+            action: SilenceAction::default(),
+        }
+    }
+}
+
+impl SilenceDetection {
+    pub const MIN_THRESHOLD_DB: f32 = -90.0;
+    pub const MAX_THRESHOLD_DB: f32 = -20.0;
+    pub const MIN_DURATION_MS: u32 = 500;
+    pub const MAX_DURATION_MS: u32 = 60_000;
+
+    /// Whether the song in `status` has been silent for long enough to be
+    /// skipped. Paused and looped songs never are.
+    pub fn triggered(&self, status: &Status) -> bool {
+        self.enabled
+            && status.playing
+            && status.ab_loop.is_none()
+            && status.silence >= Duration::from_millis(self.duration_ms as u64)
+    }
+}
+
+/// Measures how long the rendered output has stayed below the threshold.
+pub(crate) struct SilenceDetector {
+    threshold: f32,
+    ignore_dc: bool,
+    /// Running estimate of the DC offset.
+    dc: f32,
+    /// Samples in a row below the threshold.
+    silent: u64,
+}
+
+impl SilenceDetector {
+    /// Time constant of the DC estimate in seconds: slow enough to leave
+    /// the lowest notes alone, fast enough to follow volume register
+    /// changes moving the 6581 offset.
+    const DC_SMOOTHING: f32 = 0.05;
+
+    pub(crate) fn new(detection: SilenceDetection) -> Self {
+        let mut detector = Self {
+            threshold: 0.0,
+            ignore_dc: false,
+            dc: 0.0,
+            silent: 0,
+        };
+        detector.configure(detection);
+        detector
+    }
+
+    pub(crate) fn configure(&mut self, detection: SilenceDetection) {
+        self.threshold = 10f32.powf(detection.threshold_db / 20.0) * i16::MAX as f32;
+        self.ignore_dc = detection.ignore_dc;
+    }
+
+    /// Starts counting over, e.g. for another song or after seeking.
+    pub(crate) fn reset(&mut self) {
+        self.silent = 0;
+    }
+
+    pub(crate) fn process(&mut self, samples: &[i16], sample_rate: u32) {
+        let coefficient = 1.0 - (-1.0 / (Self::DC_SMOOTHING * sample_rate as f32)).exp();
+        for &sample in samples {
+            let sample = sample as f32;
+            let level = if self.ignore_dc {
+                self.dc += (sample - self.dc) * coefficient;
+                (sample - self.dc).abs()
+            } else {
+                sample.abs()
+            };
+            if level < self.threshold {
+                self.silent += 1;
+            } else {
+                self.silent = 0;
+            }
+        }
+    }
+
+    /// How long the output has been silent.
+    pub(crate) fn silence(&self, sample_rate: u32) -> Duration {
+        Duration::from_secs_f64(self.silent as f64 / sample_rate.max(1) as f64)
+    }
+}
@@ -0,0 +1,393 @@
+use crate::{
+    output::{self, DeviceOutput, OutputSelection, StreamState},
+    sid_player::SidPlayer,
+};
+use anyhow::Result;
+use log::warn;
+use ringbuf::{
+    traits::{Observer, Producer},
+    HeapProd,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::File,
+    io::{BufWriter, Seek, SeekFrom, Write},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+    thread::{self, Thread},
+    time::{Duration, Instant},
+};
+
+/// Destination of the samples rendered by [`SidPlayer`]. Samples are
+/// interleaved signed 16 bit with [`SidPlayer::CHANNELS`] channels.
+pub trait Sink: Send {
+    fn sample_rate(&self) -> u32;
+
+    /// How many samples the sink wants right now, 0 when it has enough.
+    /// Real-time sinks follow their clock; offline sinks always want more.
+    fn demand(&mut self) -> usize {
+        OFFLINE_DEMAND
+    }
+
+    fn write(&mut self, samples: &[i16]) -> Result<()>;
+
+    /// Real-time sinks hold on to what they have buffered while paused and
+    /// play it on resume.
+    fn set_paused(&mut self, _paused: bool) {}
+
+    /// Drops buffered samples that haven't been played yet.
+    fn flush(&mut self) {}
+
+    /// Called once no more samples will follow.
+    fn finish(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Which sink to render into.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub enum SinkConfig {
+    /// Real-time output on the selected sound device.
+    #[default]
+    Device,
+    /// Discards samples at real-time pace or as fast as they come.
+    Null { realtime: bool },
+    /// Writes a WAV file.
+    Wav(PathBuf),
+    /// Writes raw signed 16 bit little endian PCM to stdout.
+    Stdout,
+}
+
+/// Sink plus the sound device behind it, if any.
+pub struct Pipeline {
+    pub sink: Box<dyn Sink>,
+    pub device: Option<DeviceOutput>,
+}
+
+impl Pipeline {
+    /// Builds the configured pipeline. When the device can't be opened at all
+    /// (build servers, containers) this falls back to a real-time null sink.
+    pub fn build(config: &SinkConfig, selection: &OutputSelection) -> Result<Self> {
+        let pipeline = match config {
+            SinkConfig::Device => match DeviceOutput::open(selection) {
+                Ok((device, sink)) => Self {
+                    sink: Box::new(sink),
+                    device: Some(device),
+                },
+                Err(err) => {
+                    warn!("no usable output device, using null output: {err}");
+                    Self::headless(NullSink::new(true))
+                }
+            },
+            SinkConfig::Null { realtime } => Self::headless(NullSink::new(*realtime)),
+            SinkConfig::Wav(path) => Self::headless(WavSink::create(path)?),
+            SinkConfig::Stdout => Self::headless(StdoutSink::default()),
+        };
+        Ok(pipeline)
+    }
+
+    fn headless(sink: impl Sink + 'static) -> Self {
+        Self {
+            sink: Box::new(sink),
+            device: None,
+        }
+    }
+}
+
+const DEFAULT_SAMPLE_RATE: u32 = 44100;
+const OFFLINE_DEMAND: usize = 4096;
+
+/// Thread rendering into a [`DeviceSink`], woken by the audio callback
+/// whenever it has consumed samples.
+pub type Waker = Arc<OnceLock<Thread>>;
+
+/// Feeds the ring buffer drained by a [`DeviceOutput`]. Demand is what's
+/// missing to fill the buffer up to the target latency, so the player is
+/// paced by the sound card clock.
+pub struct DeviceSink {
+    producer: HeapProd<i16>,
+    sample_rate: Arc<AtomicU32>,
+    latency: Duration,
+    waker: Waker,
+    state: Arc<StreamState>,
+}
+
+impl DeviceSink {
+    /// Smallest demand worth waking up for.
+    const MIN_DEMAND: usize = 256;
+
+    pub fn new(
+        producer: HeapProd<i16>,
+        sample_rate: Arc<AtomicU32>,
+        latency: Duration,
+        waker: Waker,
+        state: Arc<StreamState>,
+    ) -> Self {
+        Self {
+            producer,
+            sample_rate,
+            latency,
+            waker,
+            state,
+        }
+    }
+}
+
+impl Sink for DeviceSink {
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate.load(Ordering::Relaxed)
+    }
+
+    fn demand(&mut self) -> usize {
+        self.waker.get_or_init(thread::current);
+        if self.state.flushing() {
+            // Whatever is written now would be dropped with the old samples.
+            return 0;
+        }
+        let target =
+            output::latency_frames(self.latency, self.sample_rate()) * SidPlayer::CHANNELS as usize;
+        let missing = target
+            .min(self.producer.capacity().get())
+            .saturating_sub(self.producer.occupied_len());
+        match missing {
+            missing if missing < Self::MIN_DEMAND => 0,
+            missing => missing,
+        }
+    }
+
+    fn write(&mut self, samples: &[i16]) -> Result<()> {
+        if self.producer.push_slice(samples) < samples.len() {
+            self.state.stats().overrun();
+        }
+        Ok(())
+    }
+
+    fn set_paused(&mut self, paused: bool) {
+        self.state.set_paused(paused);
+    }
+
+    fn flush(&mut self) {
+        self.state.request_flush();
+    }
+}
+
+/// Discards samples, either as fast as they come or at real-time pace,
+/// demanding only what the wall clock has consumed since the start.
+pub struct NullSink {
+    realtime: bool,
+    started: Option<Instant>,
+    consumed: u64,
+}
+
+impl NullSink {
+    pub fn new(realtime: bool) -> Self {
+        Self {
+            realtime,
+            started: None,
+            consumed: 0,
+        }
+    }
+}
+
+impl Sink for NullSink {
+    fn sample_rate(&self) -> u32 {
+        DEFAULT_SAMPLE_RATE
+    }
+
+    fn demand(&mut self) -> usize {
+        if !self.realtime {
+            return OFFLINE_DEMAND;
+        }
+        let elapsed = self.started.get_or_insert_with(Instant::now).elapsed();
+        let due = elapsed.as_micros() as u64 * DEFAULT_SAMPLE_RATE as u64 / 1_000_000
+            * SidPlayer::CHANNELS as u64;
+        due.saturating_sub(self.consumed) as usize
+    }
+
+    fn write(&mut self, samples: &[i16]) -> Result<()> {
+        self.consumed += samples.len() as u64;
+        Ok(())
+    }
+
+    fn set_paused(&mut self, paused: bool) {
+        if !paused {
+            // Start the clock over rather than catching up on the pause.
+            self.started = None;
+            self.consumed = 0;
+        }
+    }
+}
+
+/// Writes a 16 bit PCM WAV file. The header sizes are patched in
+/// [`Sink::finish`].
+pub struct WavSink {
+    writer: BufWriter<File>,
+    data_size: u32,
+}
+
+impl WavSink {
+    pub fn create(path: &PathBuf) -> Result<Self> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        let channels = SidPlayer::CHANNELS;
+        let block_align = channels * 2;
+
+        writer.write_all(b"RIFF")?;
+        writer.write_all(&36u32.to_le_bytes())?;
+        writer.write_all(b"WAVEfmt ")?;
+        writer.write_all(&16u32.to_le_bytes())?;
+        writer.write_all(&1u16.to_le_bytes())?;
+        writer.write_all(&channels.to_le_bytes())?;
+        writer.write_all(&DEFAULT_SAMPLE_RATE.to_le_bytes())?;
+        writer.write_all(&(DEFAULT_SAMPLE_RATE * block_align as u32).to_le_bytes())?;
+        writer.write_all(&block_align.to_le_bytes())?;
+        writer.write_all(&16u16.to_le_bytes())?;
+        writer.write_all(b"data")?;
+        writer.write_all(&0u32.to_le_bytes())?;
+
+        Ok(Self {
+            writer,
+            data_size: 0,
+        })
+    }
+}
+
+impl Sink for WavSink {
+    fn sample_rate(&self) -> u32 {
+        DEFAULT_SAMPLE_RATE
+    }
+
+    fn write(&mut self, samples: &[i16]) -> Result<()> {
+        for sample in samples {
+            self.writer.write_all(&sample.to_le_bytes())?;
+        }
+        self.data_size = self.data_size.saturating_add(samples.len() as u32 * 2);
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.writer.seek(SeekFrom::Start(4))?;
+        self.writer
+            .write_all(&(36u32.saturating_add(self.data_size)).to_le_bytes())?;
+        self.writer.seek(SeekFrom::Start(40))?;
+        self.writer.write_all(&self.data_size.to_le_bytes())?;
+        self.writer.seek(SeekFrom::End(0))?;
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Writes raw signed 16 bit little endian PCM to stdout, e.g. for piping
+/// into `aplay -f S16_LE -r 44100` or `ffmpeg -f s16le`.
+#[derive(Default)]
+pub struct StdoutSink {
+    buffer: Vec<u8>,
+}
+
+impl Sink for StdoutSink {
+    fn sample_rate(&self) -> u32 {
+        DEFAULT_SAMPLE_RATE
+    }
+
+    fn write(&mut self, samples: &[i16]) -> Result<()> {
+        self.buffer.clear();
+        self.buffer
+            .extend(samples.iter().flat_map(|sample| sample.to_le_bytes()));
+        std::io::stdout().lock().write_all(&self.buffer)?;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        std::io::stdout().flush()?;
+        Ok(())
+    }
+}
+
+/// Collects everything in memory, e.g. for tests and offline analysis.
+/// Clones share the same buffer.
+#[derive(Clone, Default)]
+pub struct MemorySink {
+    samples: Arc<Mutex<Vec<i16>>>,
+}
+
+impl MemorySink {
+    pub fn take(&self) -> Vec<i16> {
+        std::mem::take(&mut self.samples.lock().expect("to lock"))
+    }
+}
+
+impl Sink for MemorySink {
+    fn sample_rate(&self) -> u32 {
+        DEFAULT_SAMPLE_RATE
+    }
+
+    fn write(&mut self, samples: &[i16]) -> Result<()> {
+        self.samples
+            .lock()
+            .expect("to lock")
+            .extend_from_slice(samples);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wav_sink_patches_the_header_sizes() {
+        let path = std::env::temp_dir().join(format!("vicious-sink-{}.wav", std::process::id()));
+        let mut sink = WavSink::create(&path).unwrap();
+        sink.write(&[1, -2, 3]).unwrap();
+        sink.write(&[i16::MIN, i16::MAX]).unwrap();
+        sink.finish().unwrap();
+        let wav = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let word = |offset: usize| u16::from_le_bytes([wav[offset], wav[offset + 1]]);
+        let long = |offset: usize| u32::from_le_bytes(wav[offset..offset + 4].try_into().unwrap());
+        assert_eq!(wav.len(), 44 + 10);
+        assert_eq!(&wav[..4], b"RIFF");
+        assert_eq!(long(4), 36 + 10);
+        assert_eq!(&wav[8..16], b"WAVEfmt ");
+        assert_eq!(word(22), SidPlayer::CHANNELS);
+        assert_eq!(long(24), DEFAULT_SAMPLE_RATE);
+        assert_eq!(
+            long(28),
+            DEFAULT_SAMPLE_RATE * 2 * SidPlayer::CHANNELS as u32
+        );
+        assert_eq!(word(34), 16);
+        assert_eq!(&wav[36..40], b"data");
+        assert_eq!(long(40), 10);
+        assert_eq!(word(44) as i16, 1);
+        assert_eq!(word(46) as i16, -2);
+        assert_eq!(word(52) as i16, i16::MAX);
+    }
+
+    #[test]
+    fn null_sink_demands_what_the_clock_consumed() {
+        let mut sink = NullSink::new(true);
+        // The clock starts with the first demand.
+        assert!(sink.demand() < 441);
+        std::thread::sleep(Duration::from_millis(50));
+        let due = sink.demand();
+        // 50 ms at 44.1 kHz.
+        assert!(due >= 2205 * SidPlayer::CHANNELS as usize);
+        sink.write(&vec![0; due]).unwrap();
+        assert!(sink.demand() < due);
+
+        // Resuming starts over rather than catching up.
+        std::thread::sleep(Duration::from_millis(50));
+        sink.set_paused(false);
+        assert!(sink.demand() < 441);
+    }
+
+    #[test]
+    fn offline_null_sink_always_wants_more() {
+        let mut sink = NullSink::new(false);
+        sink.write(&[0; OFFLINE_DEMAND * 4]).unwrap();
+        assert_eq!(sink.demand(), OFFLINE_DEMAND);
+    }
+}
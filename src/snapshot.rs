@@ -0,0 +1,294 @@
+use anyhow::{ensure, Result};
+use mos6510rs::{Registers, StatusFlags};
+use resid::Sid;
+use serde::{Deserialize, Serialize};
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+/// Complete state of a [`crate::sid_player::SidPlayer`] at one point of a
+/// song: RAM (which holds the tune itself), CPU, SID and the frame timer.
+/// Restoring one needs nothing else, not even the SID file.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Snapshot {
+    #[serde(with = "base64")]
+    pub(crate) memory: Vec<u8>,
+    pub(crate) cpu: CpuState,
+    pub(crate) sid: SidState,
+    /// Exact copy including the filter and resampler, kept for snapshots
+    /// that never leave memory.
+    #[serde(skip)]
+    pub(crate) sid_copy: Option<Box<Sid>>,
+    pub(crate) init_address: u16,
+    pub(crate) play_address: u16,
+    pub(crate) songs: u16,
+    pub(crate) song: u16,
+    pub(crate) cycles: u64,
+    pub(crate) cycles_to_frame: u32,
+}
+
+impl Snapshot {
+    pub fn song(&self) -> u16 {
+        self.song
+    }
+
+    /// Emulated time into the song.
+    pub fn position(&self) -> Duration {
+        crate::sid_player::SidPlayer::cycles_to_time(self.cycles)
+    }
+
+    /// Where the quick save of a tune goes, by HVSC MD5.
+    pub fn quick_save_path(md5: &str) -> PathBuf {
+        crate::settings::config_dir()
+            .join("snapshots")
+            .join(format!("{md5}.json"))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let file = std::io::BufWriter::new(std::fs::File::create(path)?);
+        serde_json::to_writer(file, self)?;
+        Ok(())
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let file = std::io::BufReader::new(std::fs::File::open(path)?);
+        let snapshot: Self = serde_json::from_reader(file)?;
+        ensure!(snapshot.memory.len() == 0x10000, "snapshot RAM isn't 64K");
+        ensure!(
+            snapshot.sid.envelope_state.iter().all(|&state| state <= 2),
+            "snapshot has an invalid envelope state"
+        );
+        Ok(snapshot)
+    }
+}
+
+/// 6510 registers, with the flags packed the way `PHP` pushes them.
+#[derive(Clone, Serialize, Deserialize)]
+pub(crate) struct CpuState {
+    program_counter: u16,
+    stack_pointer: u8,
+    accumulator: u8,
+    x: u8,
+    y: u8,
+    flags: u8,
+}
+
+impl CpuState {
+    pub(crate) fn new(registers: &Registers, flags: &StatusFlags) -> Self {
+        let bits = [
+            flags.carry,
+            flags.zero,
+            flags.interrupt,
+            flags.decimal,
+            flags.brk,
+            flags.ignored,
+            flags.overflow,
+            flags.negative,
+        ];
+        Self {
+            program_counter: registers.program_counter,
+            stack_pointer: registers.stack_pointer,
+            accumulator: registers.accumulator,
+            x: registers.x,
+            y: registers.y,
+            flags: bits
+                .iter()
+                .enumerate()
+                .fold(0, |flags, (bit, &set)| flags | ((set as u8) << bit)),
+        }
+    }
+
+    pub(crate) fn restore(&self, registers: &mut Registers, flags: &mut StatusFlags) {
+        registers.program_counter = self.program_counter;
+        registers.stack_pointer = self.stack_pointer;
+        registers.accumulator = self.accumulator;
+        registers.x = self.x;
+        registers.y = self.y;
+        let bit = |n: u8| self.flags & (1 << n) != 0;
+        flags.carry = bit(0);
+        flags.zero = bit(1);
+        flags.interrupt = bit(2);
+        flags.decimal = bit(3);
+        flags.brk = bit(4);
+        flags.ignored = bit(5);
+        flags.overflow = bit(6);
+        flags.negative = bit(7);
+    }
+}
+
+/// Everything `resid` exports of its state: registers, oscillators and
+/// envelopes. The filter and resampler state is private to `resid`, so
+/// restoring resets both; the filter settles within milliseconds.
+#[derive(Clone, Serialize, Deserialize)]
+pub(crate) struct SidState {
+    registers: Vec<u8>,
+    bus_value: u8,
+    bus_value_ttl: u32,
+    ext_in: i32,
+    accumulator: [u32; 3],
+    shift_register: [u32; 3],
+    envelope_state: [u8; 3],
+    envelope_counter: [u8; 3],
+    exponential_counter: [u8; 3],
+    exponential_counter_period: [u8; 3],
+    hold_zero: [u8; 3],
+    rate_counter: [u16; 3],
+    rate_counter_period: [u16; 3],
+}
+
+impl SidState {
+    pub(crate) fn new(sid: &Sid) -> Self {
+        let state = sid.read_state();
+        Self {
+            registers: state.sid_register.to_vec(),
+            bus_value: state.bus_value,
+            bus_value_ttl: state.bus_value_ttl,
+            ext_in: state.ext_in,
+            accumulator: state.accumulator,
+            shift_register: state.shift_register,
+            envelope_state: state.envelope_state,
+            envelope_counter: state.envelope_counter,
+            exponential_counter: state.exponential_counter,
+            exponential_counter_period: state.exponential_counter_period,
+            hold_zero: state.hold_zero,
+            rate_counter: state.rate_counter,
+            rate_counter_period: state.rate_counter_period,
+        }
+    }
+
+    pub(crate) fn restore(&self, sid: &mut Sid) {
+        // Rather than carry on from whatever the filter of the last song
+        // was doing.
+        sid.reset();
+        // `resid` doesn't export its state type, so fill in one it made.
+        let mut state = sid.read_state();
+        for (register, &value) in state.sid_register.iter_mut().zip(&self.registers) {
+            *register = value;
+        }
+        state.bus_value = self.bus_value;
+        state.bus_value_ttl = self.bus_value_ttl;
+        state.ext_in = self.ext_in;
+        state.accumulator = self.accumulator;
+        state.shift_register = self.shift_register;
+        state.envelope_state = self.envelope_state;
+        state.envelope_counter = self.envelope_counter;
+        state.exponential_counter = self.exponential_counter;
+        state.exponential_counter_period = self.exponential_counter_period;
+        state.hold_zero = self.hold_zero;
+        state.rate_counter = self.rate_counter;
+        state.rate_counter_period = self.rate_counter_period;
+        sid.write_state(&state);
+    }
+}
+
+/// RAM as a base64 string instead of a JSON array of 64K numbers.
+mod base64 {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        let mut text = String::with_capacity(bytes.len().div_ceil(3) * 4);
+        for chunk in bytes.chunks(3) {
+            let bits = chunk.iter().enumerate().fold(0u32, |bits, (i, &byte)| {
+                bits | (byte as u32) << (16 - 8 * i)
+            });
+            for i in 0..4 {
+                text.push(match i <= chunk.len() {
+                    true => ALPHABET[(bits >> (18 - 6 * i) & 0x3f) as usize] as char,
+                    false => '=',
+                });
+            }
+        }
+        serializer.serialize_str(&text)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        let text = text.trim_end_matches('=');
+        let mut bytes = Vec::with_capacity(text.len() * 3 / 4);
+        for chunk in text.as_bytes().chunks(4) {
+            let mut bits = 0u32;
+            for (i, &digit) in chunk.iter().enumerate() {
+                let value = match digit {
+                    b'A'..=b'Z' => digit - b'A',
+                    b'a'..=b'z' => digit - b'a' + 26,
+                    b'0'..=b'9' => digit - b'0' + 52,
+                    b'+' => 62,
+                    b'/' => 63,
+                    _ => return Err(D::Error::custom("invalid base64")),
+                };
+                bits |= (value as u32) << (18 - 6 * i);
+            }
+            if chunk.len() == 1 {
+                return Err(D::Error::custom("truncated base64"));
+            }
+            bytes.extend(&bits.to_be_bytes()[1..chunk.len()]);
+        }
+        Ok(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Bytes(#[serde(with = "base64")] Vec<u8>);
+
+    #[test]
+    fn ram_is_base64() {
+        for (bytes, text) in [
+            (&b""[..], ""),
+            (b"f", "Zg=="),
+            (b"fo", "Zm8="),
+            (b"foo", "Zm9v"),
+            (b"\xff\x00\xfe\x01", "/wD+AQ=="),
+        ] {
+            let json = serde_json::to_string(&Bytes(bytes.to_vec())).unwrap();
+            assert_eq!(json, format!("\"{text}\""));
+            let decoded: Bytes = serde_json::from_str(&json).unwrap();
+            assert_eq!(decoded.0, bytes);
+        }
+        let memory: Vec<u8> = (0..0x10000).map(|i| (i * 7 % 251) as u8).collect();
+        let json = serde_json::to_vec(&Bytes(memory.clone())).unwrap();
+        let decoded: Bytes = serde_json::from_reader(&json[..]).unwrap();
+        assert_eq!(decoded.0, memory);
+        assert!(serde_json::from_str::<Bytes>("\"Zm9v!\"").is_err());
+        assert!(serde_json::from_str::<Bytes>("\"Zm9vZ\"").is_err());
+    }
+
+    #[test]
+    fn saves_and_loads() {
+        use crate::{sid_player::SidPlayer, sink::NullSink};
+        let mut player = SidPlayer::new(Box::new(NullSink::new(false)));
+        player.load_data(
+            &[0x60, 0xee, 0x00, 0x20, 0x60],
+            0x1000,
+            0x1000,
+            0x1001,
+            2,
+            2,
+        );
+        player.pump();
+        let snapshot = player.snapshot();
+        let path = std::env::temp_dir().join(format!("vicious-{}.snapshot", std::process::id()));
+        snapshot.save(&path).unwrap();
+        let size = std::fs::metadata(&path).unwrap().len();
+        let loaded = Snapshot::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(size < 0x10000 * 4 / 3 + 1024, "{size} bytes");
+        assert_eq!(loaded.memory, snapshot.memory);
+        assert_eq!(loaded.song(), 2);
+        assert_eq!(loaded.position(), snapshot.position());
+        assert!(loaded.sid_copy.is_none());
+
+        player.restore(&loaded);
+        assert_eq!(player.memory(), &snapshot.memory[..]);
+        assert_eq!(player.position(), snapshot.position());
+    }
+}
@@ -0,0 +1,156 @@
+use anyhow::{Context, Result};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+/// How long a song plays in a playlist when its length isn't known.
+pub const DEFAULT_SONG_LENGTH: Duration = Duration::from_secs(180);
+
+/// Song lengths by HVSC MD5, first song first, as listed in HVSC's
+/// `DOCUMENTS/Songlengths.md5`. Older releases key tunes by the old MD5,
+/// HVSC 68 and later by the full file one, see [`crate::hvsc`].
+#[derive(Debug, Clone, Default)]
+pub struct SongLengths {
+    tunes: HashMap<String, Vec<Duration>>,
+}
+
+impl SongLengths {
+    pub const FILE_NAME: &str = "Songlengths.md5";
+    /// Where HVSC keeps the database, relative to the collection root.
+    pub const HVSC_PATH: &str = "DOCUMENTS/Songlengths.md5";
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("can't read {}", path.display()))?;
+        Ok(Self::parse(&text))
+    }
+
+    /// Looks for the database of the HVSC collection `sid_path` is part of,
+    /// in the directories above it, either where HVSC ships it or next to
+    /// the collection as `download.sh` leaves it.
+    pub fn find(sid_path: &Path) -> Option<PathBuf> {
+        sid_path
+            .ancestors()
+            .skip(1)
+            .flat_map(|dir| [dir.join(Self::HVSC_PATH), dir.join(Self::FILE_NAME)])
+            .find(|path| path.is_file())
+    }
+
+    /// Parses `md5=m:ss m:ss.mmm ...` lines. Section headers, `;` comments
+    /// and old style attributes such as `(G)` are skipped.
+    pub fn parse(text: &str) -> Self {
+        let tunes = text
+            .lines()
+            .filter_map(|line| line.trim().split_once('='))
+            .filter(|(md5, _)| md5.len() == 32)
+            .map(|(md5, lengths)| {
+                let lengths = lengths.split_whitespace().filter_map(parse_time).collect();
+                (md5.to_ascii_lowercase(), lengths)
+            })
+            .collect();
+        Self { tunes }
+    }
+
+    pub fn get(&self, md5: &str, song: u16) -> Option<Duration> {
+        let lengths = self.tunes.get(md5)?;
+        lengths.get(song.checked_sub(1)? as usize).copied()
+    }
+
+    /// Looks `song` up by either fingerprint of a tune.
+    pub fn get_either(&self, md5: &str, full_md5: &str, song: u16) -> Option<Duration> {
+        self.get(md5, song).or_else(|| self.get(full_md5, song))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tunes.is_empty()
+    }
+}
+
+/// Parses `m:ss` or `m:ss.mmm`, ignoring a trailing attribute in brackets.
+fn parse_time(text: &str) -> Option<Duration> {
+    let text = text.split('(').next()?;
+    let (minutes, seconds) = text.split_once(':')?;
+    let minutes: u64 = minutes.parse().ok()?;
+    let seconds: f64 = seconds.parse().ok()?;
+    let seconds = Duration::try_from_secs_f64(seconds).ok()?;
+    Duration::from_secs(minutes.checked_mul(60)?).checked_add(seconds)
+}
+
+/// Formats as `m:ss.mmm`, the way `Songlengths.md5` lists lengths.
+pub fn format_precise_time(time: Duration) -> String {
+    let millis = time.as_millis();
+    format!(
+        "{}:{:02}.{:03}",
+        millis / 60_000,
+        millis / 1000 % 60,
+        millis % 1000
+    )
+}
+
+/// Formats as `m:ss`, the way song lengths are usually shown.
+pub fn format_time(time: Duration) -> String {
+    let seconds = time.as_secs();
+    format!("{}:{:02}", seconds / 60, seconds % 60)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DATABASE: &str = "\
+; /MUSICIANS/H/Hubbard_Rob/Commando.sid
+[Database]
+0123456789ABCDEF0123456789abcdef=3:12 0:05.250 1:00(G) 0:30(M)
+; broken lines are skipped
+short=1:00
+fedcba9876543210fedcba9876543210=junk 2:00
+";
+
+    #[test]
+    fn parses_lengths_by_md5() {
+        let lengths = SongLengths::parse(DATABASE);
+        let md5 = "0123456789abcdef0123456789abcdef";
+        assert_eq!(lengths.get(md5, 1), Some(Duration::from_secs(192)));
+        assert_eq!(lengths.get(md5, 2), Some(Duration::from_millis(5250)));
+        assert_eq!(lengths.get(md5, 3), Some(Duration::from_secs(60)));
+        assert_eq!(lengths.get(md5, 4), Some(Duration::from_secs(30)));
+        assert_eq!(lengths.get(md5, 5), None);
+        assert_eq!(lengths.get(md5, 0), None);
+        // Unreadable lengths drop out.
+        let other = "fedcba9876543210fedcba9876543210";
+        assert_eq!(lengths.get(other, 1), Some(Duration::from_secs(120)));
+        assert_eq!(lengths.get("short", 1), None);
+    }
+
+    #[test]
+    fn looks_up_either_fingerprint() {
+        let lengths = SongLengths::parse(DATABASE);
+        let md5 = "0123456789abcdef0123456789abcdef";
+        assert_eq!(
+            lengths.get_either("unknown", md5, 1),
+            Some(Duration::from_secs(192))
+        );
+        assert_eq!(
+            lengths.get_either(md5, "unknown", 2),
+            Some(Duration::from_millis(5250))
+        );
+        assert_eq!(lengths.get_either("unknown", "unknown", 1), None);
+    }
+
+    #[test]
+    fn parses_and_formats_times() {
+        assert_eq!(parse_time("12:01.5"), Some(Duration::from_millis(721_500)));
+        assert_eq!(parse_time("0:07(S)"), Some(Duration::from_secs(7)));
+        assert_eq!(parse_time("7"), None);
+        for broken in ["1:-5", "1:inf", "1:nan"] {
+            assert_eq!(parse_time(broken), None, "{broken}");
+        }
+        assert_eq!(format_time(Duration::from_millis(721_500)), "12:01");
+        assert_eq!(
+            format_precise_time(Duration::from_millis(65_007)),
+            "1:05.007"
+        );
+    }
+}
@@ -0,0 +1,123 @@
+use crate::sid_player::SidPlayer;
+use serde::{Deserialize, Serialize};
+use std::f32::consts::FRAC_PI_2;
+
+/// How one tune gives way to the next when a playlist moves on.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum TransitionMode {
+    /// Drops what is still buffered of the old tune and starts right away.
+    #[default]
+    Cut,
+    /// The new tune follows the buffered end of the old one seamlessly.
+    Gapless,
+    /// The old tune fades out, then the new one fades in.
+    Fade,
+    /// Both tunes play at once while one fades into the other.
+    Crossfade,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Transition {
+    pub mode: TransitionMode,
+    /// Length of each fade, or of the overlap when crossfading.
+    pub duration_ms: u32,
+}
+
+impl Default for Transition {
+    fn default() -> Self {
+        Self {
+            mode: TransitionMode::default(),
+            duration_ms: 2000,
+        }
+    }
+}
+
+impl Transition {
+    pub const MIN_DURATION_MS: u32 = 50;
+    pub const MAX_DURATION_MS: u32 = 10_000;
+}
+
+/// A fade in progress: the outgoing tune, emulated by a player of its own,
+/// and how far along it is.
+pub(crate) struct Fade {
+    outgoing: Box<SidPlayer>,
+    mode: TransitionMode,
+    /// Samples per fade.
+    length: usize,
+    elapsed: usize,
+    buffer: Vec<i16>,
+}
+
+impl Fade {
+    pub(crate) fn new(outgoing: SidPlayer, transition: Transition, sample_rate: u32) -> Self {
+        let length = transition.duration_ms as usize * sample_rate as usize / 1000
+            * SidPlayer::CHANNELS as usize;
+        Self {
+            outgoing: Box::new(outgoing),
+            mode: transition.mode,
+            length: length.max(1),
+            elapsed: 0,
+            buffer: Vec::new(),
+        }
+    }
+
+    fn total(&self) -> usize {
+        match self.mode {
+            TransitionMode::Fade => 2 * self.length,
+            _ => self.length,
+        }
+    }
+
+    pub(crate) fn is_done(&self) -> bool {
+        self.elapsed >= self.total()
+    }
+
+    /// Fills `output` with the transition from the outgoing tune to
+    /// `incoming`, and with `incoming` alone once it's over.
+    pub(crate) fn render(&mut self, incoming: &mut SidPlayer, output: &mut [i16]) {
+        let mut done = 0;
+        while done < output.len() {
+            let total = self.total();
+            if self.elapsed >= total {
+                incoming.render(&mut output[done..]);
+                return;
+            }
+            let fading_out = self.mode == TransitionMode::Fade && self.elapsed < self.length;
+            let phase_end = if fading_out { self.length } else { total };
+            let count = (output.len() - done).min(phase_end - self.elapsed);
+            let chunk = &mut output[done..done + count];
+            let (elapsed, length) = (self.elapsed, self.length);
+            let progress =
+                |index: usize, start: usize| (elapsed + index - start) as f32 / length as f32;
+
+            match self.mode {
+                TransitionMode::Crossfade => {
+                    incoming.render(chunk);
+                    self.buffer.resize(count, 0);
+                    self.outgoing.render(&mut self.buffer);
+                    // Equal power, so the overlap doesn't dip in loudness.
+                    for (index, (sample, old)) in chunk.iter_mut().zip(&self.buffer).enumerate() {
+                        let angle = progress(index, 0) * FRAC_PI_2;
+                        let mixed = *sample as f32 * angle.sin() + *old as f32 * angle.cos();
+                        *sample = mixed.clamp(i16::MIN as f32, i16::MAX as f32) as i16;
+                    }
+                }
+                _ if fading_out => {
+                    self.outgoing.render(chunk);
+                    for (index, sample) in chunk.iter_mut().enumerate() {
+                        *sample = (*sample as f32 * (1.0 - progress(index, 0))) as i16;
+                    }
+                }
+                _ => {
+                    incoming.render(chunk);
+                    for (index, sample) in chunk.iter_mut().enumerate() {
+                        *sample = (*sample as f32 * progress(index, self.length)) as i16;
+                    }
+                }
+            }
+            self.elapsed += count;
+            done += count;
+        }
+    }
+}
@@ -0,0 +1,108 @@
+use serde::{Deserialize, Serialize};
+
+/// Master volume, persisted with the other settings.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Volume {
+    /// Gain in decibels, [`Volume::MIN_DB`] meaning silence.
+    pub db: f32,
+    pub muted: bool,
+    /// Soft-clips peaks instead of letting loud mixes wrap or hard clip.
+    pub limiter: bool,
+}
+
+impl Default for Volume {
+    fn default() -> Self {
+        Self {
+            db: 0.0,
+            muted: false,
+            limiter: false,
+        }
+    }
+}
+
+impl Volume {
+    pub const MIN_DB: f32 = -60.0;
+    pub const MAX_DB: f32 = 12.0;
+    /// Change per key press or mouse wheel notch.
+    pub const STEP_DB: f32 = 1.0;
+
+    /// Linear gain factor.
+    pub fn gain(&self) -> f32 {
+        if self.muted || self.db <= Self::MIN_DB {
+            0.0
+        } else {
+            10f32.powf(self.db / 20.0)
+        }
+    }
+
+    pub fn adjust(&mut self, delta_db: f32) {
+        self.db = (self.db + delta_db).clamp(Self::MIN_DB, Self::MAX_DB);
+    }
+}
+
+/// Applies a [`Volume`] to rendered samples. Gain changes are smoothed over
+/// a few milliseconds so moving the slider doesn't produce zipper noise.
+pub struct Gain {
+    volume: Volume,
+    /// Loudness normalization on top of the volume, linear.
+    normalization: f32,
+    current: f32,
+}
+
+impl Gain {
+    /// Time constant of the gain smoothing, in seconds.
+    const SMOOTHING: f32 = 0.01;
+    /// Level above which the limiter starts to bend the signal.
+    const LIMITER_THRESHOLD: f32 = 0.7;
+
+    pub fn new(volume: Volume) -> Self {
+        Self {
+            volume,
+            normalization: 1.0,
+            current: volume.gain(),
+        }
+    }
+
+    pub fn set_volume(&mut self, volume: Volume) {
+        self.volume = volume;
+    }
+
+    pub fn set_normalization(&mut self, gain_db: f32) {
+        self.normalization = 10f32.powf(gain_db / 20.0);
+    }
+
+    pub fn process(&mut self, samples: &mut [i16], sample_rate: u32) {
+        let target = self.volume.gain() * self.normalization;
+        if self.current == target && target == 1.0 && !self.volume.limiter {
+            return;
+        }
+
+        let coefficient = 1.0 - (-1.0 / (Self::SMOOTHING * sample_rate as f32)).exp();
+        for sample in samples {
+            self.current += (target - self.current) * coefficient;
+            if (target - self.current).abs() < 1e-5 {
+                self.current = target;
+            }
+
+            let mut value = *sample as f32 / -(i16::MIN as f32) * self.current;
+            if self.volume.limiter {
+                value = Self::limit(value);
+            }
+            *sample = (value * -(i16::MIN as f32)).clamp(i16::MIN as f32, i16::MAX as f32) as i16;
+        }
+    }
+
+    /// Passes the signal unchanged below the threshold and approaches full
+    /// scale asymptotically above it.
+    fn limit(value: f32) -> f32 {
+        let magnitude = value.abs();
+        if magnitude <= Self::LIMITER_THRESHOLD {
+            return value;
+        }
+        let headroom = 1.0 - Self::LIMITER_THRESHOLD;
+        let limited = Self::LIMITER_THRESHOLD
+            + headroom * ((magnitude - Self::LIMITER_THRESHOLD) / headroom).tanh();
+        limited.copysign(value)
+    }
+}